@@ -0,0 +1,228 @@
+//! Keeps track of the layout of the cache directory (`test_dir`),
+//! so a cache can be reused across machines, and a cache written
+//! by an incompatible version of keeper is thrown away.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The name of the index file, inside `test_dir`.
+pub const INDEX_FILE: &str = "keeper_cache.json";
+
+/// The name of the file recording which error codes each `compile_fail`
+/// block failed with, inside `test_dir`.
+pub const ERROR_CODES_FILE: &str = "keeper_error_codes.json";
+
+/// The name of the file recording the code hash of each test which passed
+/// the last time it ran, keyed by test name, inside `test_dir`.
+pub const PASSED_FILE: &str = "keeper_passed.json";
+
+/// The name of the file recording whether each test passed the last
+/// time it ran, keyed by test name, inside `test_dir`.
+pub const STATUSES_FILE: &str = "keeper_statuses.json";
+
+/// The name of the file naming the book the cache in `test_dir` belongs
+/// to, so a `test_dir` shared with another book isn't cleaned up.
+pub const OWNER_FILE: &str = "keeper_owner.txt";
+
+/// The name of the file holding the tests found in each chapter, keyed
+/// by a hash of the chapter, so unchanged chapters needn't be parsed again.
+pub const CHAPTERS_FILE: &str = "keeper_chapters.json";
+
+/// The start of the names of files for tests which are still being
+/// compiled and run. These are left behind if keeper crashes.
+pub const PENDING_PREFIX: &str = "pending_";
+
+/// How the hashes in cache file names are computed.
+const HASH_ALGORITHM: &str = "sha256-base64url";
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct CacheIndex {
+    version: String,
+    hash_algorithm: String,
+}
+
+impl CacheIndex {
+    fn current() -> CacheIndex {
+        CacheIndex {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hash_algorithm: HASH_ALGORITHM.to_string(),
+        }
+    }
+
+    fn read(test_dir: &Path) -> Option<CacheIndex> {
+        let contents = fs::read_to_string(test_dir.join(INDEX_FILE)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+}
+
+/// The paths in `test_dir` matching the glob `pattern`, whether they're
+/// directly inside it or in one of the two-character shard directories
+/// used with `shard_cache`.
+pub fn cache_files(test_dir: &Path, pattern: &str) -> impl Iterator<Item = PathBuf> {
+    let flat = format!("{}/{}", test_dir.display(), pattern);
+    let sharded = format!("{}/??/{}", test_dir.display(), pattern);
+    [flat, sharded].into_iter().flat_map(|glob_str| {
+        glob(&glob_str)
+            .expect("Could not list keeper files.")
+            .filter_map(Result::ok)
+    })
+}
+
+/// Makes sure the cache in `test_dir` was written by this version of
+/// keeper. If it wasn't (or there's no index, because it was written
+/// by an older version), every cached test is removed, so nothing is
+/// falsely reported as having passed. This must happen before anything
+/// else is written to `test_dir`, or that is removed too.
+///
+/// The cache of another book (see [`read_owner`]) is left for that book
+/// to validate, and which book owns the cache is kept.
+pub fn validate_cache_index(test_dir: &Path, book: &str) -> Result<(), std::io::Error> {
+    // Nothing has been cached yet; the index is written with the cache.
+    if !test_dir.is_dir() {
+        return Ok(());
+    }
+
+    let current = CacheIndex::current();
+    if CacheIndex::read(test_dir).as_ref() == Some(&current) {
+        return Ok(());
+    }
+    if read_owner(test_dir).is_some_and(|owner| owner != book) {
+        return Ok(());
+    }
+
+    let owner_file = test_dir.join(OWNER_FILE);
+    for path in cache_files(test_dir, "keeper_*").filter(|p| p.is_file() && *p != owner_file) {
+        fs::remove_file(path)?;
+    }
+
+    let index = serde_json::to_string_pretty(&current).expect("index can be serialized");
+    fs::write(test_dir.join(INDEX_FILE), index)
+}
+
+/// Creates `test_dir`, if it doesn't exist yet, with an index saying
+/// which version of keeper wrote it.
+pub fn create_dir(test_dir: &Path) -> Result<(), std::io::Error> {
+    fs::create_dir_all(test_dir)?;
+    if test_dir.join(INDEX_FILE).is_file() {
+        return Ok(());
+    }
+    let index =
+        serde_json::to_string_pretty(&CacheIndex::current()).expect("index can be serialized");
+    fs::write(test_dir.join(INDEX_FILE), index)
+}
+
+/// Reads the error codes each `compile_fail` block failed with the last
+/// time it was compiled, keyed by code hash (names change when a block
+/// moves, but its code doesn't).
+pub fn read_error_codes(test_dir: &Path) -> HashMap<String, Vec<String>> {
+    fs::read_to_string(test_dir.join(ERROR_CODES_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_error_codes(
+    test_dir: &Path,
+    error_codes: &HashMap<String, Vec<String>>,
+) -> Result<(), std::io::Error> {
+    let contents = serde_json::to_string_pretty(error_codes).expect("codes can be serialized");
+    fs::write(test_dir.join(ERROR_CODES_FILE), contents)
+}
+
+/// Reads the code hash of each test which passed the last time it ran,
+/// keyed by test name.
+pub fn read_passed(test_dir: &Path) -> HashMap<String, String> {
+    fs::read_to_string(test_dir.join(PASSED_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_passed(
+    test_dir: &Path,
+    passed: &HashMap<String, String>,
+) -> Result<(), std::io::Error> {
+    let contents = serde_json::to_string_pretty(passed).expect("hashes can be serialized");
+    fs::write(test_dir.join(PASSED_FILE), contents)
+}
+
+/// Reads whether each test passed the last time it ran, keyed by test
+/// name, if they've been recorded.
+pub fn read_statuses(test_dir: &Path) -> Option<HashMap<String, bool>> {
+    fs::read_to_string(test_dir.join(STATUSES_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+}
+
+pub fn write_statuses(
+    test_dir: &Path,
+    statuses: &HashMap<String, bool>,
+) -> Result<(), std::io::Error> {
+    let contents = serde_json::to_string_pretty(statuses).expect("statuses can be serialized");
+    fs::write(test_dir.join(STATUSES_FILE), contents)
+}
+
+/// Reads what was found in each chapter last time, keyed by a hash of
+/// the chapter.
+pub fn read_chapters<T: DeserializeOwned>(test_dir: &Path) -> HashMap<String, T> {
+    fs::read_to_string(test_dir.join(CHAPTERS_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_chapters<T: Serialize>(
+    test_dir: &Path,
+    chapters: &HashMap<String, T>,
+) -> Result<(), std::io::Error> {
+    create_dir(test_dir)?;
+    let contents = serde_json::to_string(chapters).expect("chapters can be serialized");
+    fs::write(test_dir.join(CHAPTERS_FILE), contents)
+}
+
+/// The book the cache in `test_dir` belongs to, if it's been claimed.
+pub fn read_owner(test_dir: &Path) -> Option<String> {
+    fs::read_to_string(test_dir.join(OWNER_FILE))
+        .ok()
+        .map(|owner| owner.trim().to_string())
+}
+
+pub fn write_owner(test_dir: &Path, book: &str) -> Result<(), std::io::Error> {
+    fs::write(test_dir.join(OWNER_FILE), format!("{}\n", book))
+}
+
+/// Removes everything keeper keeps in `test_dir`: the cached tests and
+/// their build artifacts, the files recording what happened to them, and
+/// the directories tests were compiled and run in. Anything else (such as
+/// the `target` directory) is left. Returns how many files were removed.
+pub fn clear(test_dir: &Path) -> Result<usize, std::io::Error> {
+    let mut removed = 0;
+    for pattern in ["keeper_*", &format!("{}*", PENDING_PREFIX)] {
+        for path in cache_files(test_dir, pattern).filter(|p| p.is_file()) {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+
+    for dir in ["incremental", "run"] {
+        let dir = test_dir.join(dir);
+        if dir.is_dir() {
+            fs::remove_dir_all(dir)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes the files left behind by tests which never finished,
+/// because keeper crashed (or was stopped) while running them.
+pub fn remove_pending_files(test_dir: &Path) -> Result<(), std::io::Error> {
+    for path in cache_files(test_dir, &format!("{}*", PENDING_PREFIX)).filter(|p| p.is_file()) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
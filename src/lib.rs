@@ -1,55 +1,440 @@
+mod cache;
 mod run_tests;
 mod skeptic;
 
 #[cfg(test)]
 mod tests;
 
-use std::{fs::File, io::prelude::*};
+use std::{
+    fs::File,
+    io::{self, prelude::*, BufReader},
+};
 
 use atty::Stream;
 use colored::{control::set_override, Colorize};
-use glob::glob;
 use mdbook::{
-    book::{Book, BookItem},
+    book::{Book, BookItem, Chapter},
     errors::Error,
     preprocess::{Preprocessor, PreprocessorContext},
 };
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use slug::slugify;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command, Stdio},
+    sync::{Mutex, MutexGuard, OnceLock, PoisonError},
+    thread,
+    time::{Duration, Instant},
 };
+use tempfile::TempDir;
 use toml::value::Table;
 
-use run_tests::{handle_test, CompileType, TestResult};
-use skeptic::{create_test_input, extract_tests_from_string, Test};
+use run_tests::{
+    chapter_lines, dependency_names, dependency_versions, describe_exit, error_codes, extern_glob,
+    fixture_fingerprint, handle_test, is_panic, is_sanitizer_error, manifest_edition,
+    missing_dependency_artifacts, normalize_output, panic_message_matches, parse_extern_alias,
+    prebuilt_fingerprint, project_fingerprint, read_deps_manifest, target_is_installed,
+    EditionStrategy, SkipReason, DEFAULT_EDITION,
+};
+use skeptic::{
+    annotate_blocks, create_rustdoc_test_input, create_test_input, extract_tests_from_string,
+    get_hash, hidden_line_hint, is_fragment, remove_hidden_blocks, sanitize_test_name,
+    strip_comments_and_literals, uses_dependencies, uses_test_harness, MainWrapper,
+    DEFAULT_WRAPPER_TEMPLATE,
+};
+
+pub use run_tests::{
+    compile_test, run_compiled, run_compiled_timed, CompileType, CompiledTest, Outcome, RunTimes,
+    TestResult,
+};
+pub use skeptic::Test;
 
 type PreprocessorConfig<'a> = Option<&'a Table>;
 
-fn get_tests_from_book(book: &Book) -> Vec<Test> {
-    get_tests_from_items(&book.sections)
+/// How many lines of cargo's output to show when building the project fails.
+const CARGO_ERROR_LINES: usize = 40;
+
+/// Somewhere tests can come from. mdbook's [`Book`] is one, but other
+/// documentation tools can use keeper by providing their own.
+pub trait TestSource {
+    /// The tests to run, found with the options in `config`.
+    fn tests(&self, config: &KeeperConfig) -> Result<Vec<Test>, Error>;
+}
+
+impl TestSource for &Book {
+    fn tests(&self, config: &KeeperConfig) -> Result<Vec<Test>, Error> {
+        get_tests_from_book(self, config)
+    }
+}
+
+/// Finds the tests in `markdown`, as if it were a chapter of the book
+/// called `file`. [`TestSource`]s outside keeper can use this to turn
+/// their documentation into tests.
+pub fn tests_from_markdown(markdown: &str, file: &str, config: &KeeperConfig) -> Vec<Test> {
+    let (mut tests, _) =
+        extract_tests_from_string(markdown, file, config.accumulate, config.rustdoc_compat);
+    for test in &mut tests {
+        config.apply_chapter_attributes(test);
+    }
+    tests
+}
+
+/// Finds every test in `book`.
+pub fn get_tests_from_book(book: &Book, config: &KeeperConfig) -> Result<Vec<Test>, Error> {
+    let (tests, templates) = get_tests_and_templates_from_book(book, config)?;
+    warn_about_old_templates(&tests, &templates.old);
+    Ok(tests)
+}
+
+/// The `skeptic-template` blocks found in a book.
+#[derive(Debug, Default)]
+struct BookTemplates {
+    /// The templates given a name (with `skt-<name>`), by name.
+    named: HashMap<String, String>,
+    /// The template without a name each chapter defined, which skeptic
+    /// applies to every block in the chapter, keyed by the chapter's file.
+    old: HashMap<String, String>,
+}
+
+/// Finds every test in `book`, and the templates they may use.
+fn get_tests_and_templates_from_book(
+    book: &Book,
+    config: &KeeperConfig,
+) -> Result<(Vec<Test>, BookTemplates), Error> {
+    let mut tests = Vec::new();
+    let mut templates = BookTemplates::default();
+    let mut chapters = ParsedChapters {
+        previous: cache::read_chapters(&config.test_dir),
+        current: HashMap::new(),
+    };
+    get_tests_from_items(
+        &book.sections,
+        config,
+        &mut tests,
+        &mut templates,
+        &mut chapters,
+    );
+
+    // A README outside the book is tested as if it were another chapter.
+    if let Some(readme_path) = &config.readme_path {
+        let content = std::fs::read_to_string(readme_path).map_err(|e| {
+            Error::msg(format!(
+                "couldn't read readme_path `{}`: {}",
+                readme_path.display(),
+                e
+            ))
+        })?;
+        let readme_name = config.readme_name.as_deref().unwrap_or(readme_path);
+        let readme = BookItem::Chapter(Chapter::new("README", content, readme_name, Vec::new()));
+        get_tests_from_items(&[readme], config, &mut tests, &mut templates, &mut chapters);
+    }
+
+    // Only worth saving if a chapter changed (or went away).
+    let changed = chapters.current.len() != chapters.previous.len()
+        || chapters
+            .current
+            .keys()
+            .any(|key| !chapters.previous.contains_key(key));
+    // An audit only reports on the book, so leaves the cache as it was.
+    if changed && !tests.is_empty() && !config.audit {
+        if let Err(e) = cache::write_chapters(&config.test_dir, &chapters.current) {
+            eprintln!("Could not save the tests found in each chapter: {}", e);
+        }
+    }
+
+    // Chapters were visited in the book's order, as were their tests.
+    let mut positions = HashMap::new();
+    for test in &mut tests {
+        let next = positions.len();
+        test.file_position = *positions.entry(test.file.clone()).or_insert(next);
+    }
+
+    check_templates(&tests, &templates.named, config)?;
+    warn_about_unexpanded_links(&tests);
+    check_forbidden_patterns(&tests, config)?;
+
+    Ok((tests, templates))
+}
+
+/// Each test from `source`, along with the exact source keeper compiles
+/// for it (with hidden lines revealed, and any wrapping or template
+/// applied), without compiling anything. This lets other tools show what
+/// keeper tests, or make playground links.
+pub fn collect_tests(
+    source: impl TestSource,
+    config: &KeeperConfig,
+) -> Result<Vec<(Test, String)>, Error> {
+    Ok(source
+        .tests(config)?
+        .into_iter()
+        .map(|test| {
+            let source = test_source(&test, config);
+            (test, source)
+        })
+        .collect())
+}
+
+/// What was found in a chapter, saved so the chapter needn't be parsed
+/// again until it changes.
+#[derive(Clone, Deserialize, Serialize)]
+struct ParsedChapter {
+    tests: Vec<Test>,
+    templates: HashMap<String, String>,
+    old_template: Option<String>,
+}
+
+/// The chapters parsed last time, and the ones seen this time, keyed by
+/// [`chapter_key`].
+struct ParsedChapters {
+    previous: HashMap<String, ParsedChapter>,
+    current: HashMap<String, ParsedChapter>,
 }
 
-fn get_tests_from_items(items: &[BookItem]) -> Vec<Test> {
+/// Identifies a chapter's content (and the options which change how it's
+/// parsed), for [`ParsedChapters`].
+fn chapter_key(file_name: &str, content: &str, config: &KeeperConfig) -> String {
+    get_hash(&format!(
+        "{}\0{}\0{}\0{}\0{}",
+        env!("CARGO_PKG_VERSION"),
+        file_name,
+        config.accumulate,
+        config.rustdoc_compat,
+        content
+    ))
+}
+
+fn get_tests_from_items(
+    items: &[BookItem],
+    config: &KeeperConfig,
+    tests: &mut Vec<Test>,
+    templates: &mut BookTemplates,
+    parsed_chapters: &mut ParsedChapters,
+) {
+    // Prefix and suffix chapters (like a preface) are chapters too. The
+    // other items, separators and part titles, have no content.
     let chapters = items.iter().filter_map(|b| match *b {
         BookItem::Chapter(ref ch) => Some(ch),
         _ => None,
     });
 
-    chapters
-        .flat_map(|c| {
-            let file_name = c
-                .path
-                .as_ref()
-                .map(|x| x.to_string_lossy().into_owned())
-                .unwrap_or_else(|| slugify(c.name.clone()).replace('-', "_"));
-            let (mut tests, _) = extract_tests_from_string(&c.content, &file_name);
-            tests.append(&mut get_tests_from_items(&c.sub_items));
-            tests
-        })
-        .collect::<Vec<_>>()
+    for c in chapters {
+        let file_name = chapter_file_name(c);
+        let key = chapter_key(&file_name, &c.content, config);
+        let parsed = match parsed_chapters.previous.get(&key) {
+            Some(parsed) => parsed.clone(),
+            None => {
+                let (tests, templates) = extract_tests_from_string(
+                    &c.content,
+                    &file_name,
+                    config.accumulate,
+                    config.rustdoc_compat,
+                );
+                ParsedChapter {
+                    tests,
+                    templates: templates.named,
+                    old_template: templates.old_template,
+                }
+            }
+        };
+        parsed_chapters.current.insert(key, parsed.clone());
+
+        let mut chapter_tests = parsed.tests;
+        for test in &mut chapter_tests {
+            config.apply_chapter_attributes(test);
+        }
+        tests.append(&mut chapter_tests);
+        templates.named.extend(parsed.templates);
+        if let Some(old_template) = parsed.old_template {
+            templates.old.insert(file_name, old_template);
+        }
+        get_tests_from_items(&c.sub_items, config, tests, templates, parsed_chapters);
+    }
+}
+
+/// The name tests from chapter `c` give as their file. This is its path
+/// within the book's source directory (not just its file name), so
+/// chapters with the same name in different directories are told apart.
+fn chapter_file_name(c: &Chapter) -> String {
+    c.path
+        .as_ref()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|| slugify(c.name.clone()).replace('-', "_"))
+}
+
+/// Makes sure every test which uses a named template (with `skt-<name>`)
+/// refers to a template that was actually defined somewhere in the book.
+fn check_templates(
+    tests: &[Test],
+    templates: &HashMap<String, String>,
+    config: &KeeperConfig,
+) -> Result<(), Error> {
+    // Blocks which won't be compiled don't need their template.
+    for test in tests.iter().filter(|test| config.will_run(test)) {
+        if let Some(template) = &test.template {
+            if !templates.contains_key(template) {
+                return Err(Error::msg(format!(
+                    "The code block {} uses the template `{}`, but no template \
+                     with that name was defined (with `skeptic-template,skt-{}`).",
+                    test.name, template, template
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The start of mdbook's links which bring code in from other files.
+const INCLUDE_LINKS: [&str; 3] = ["{{#include", "{{#rustdoc_include", "{{#playground"];
+
+/// Warns about code blocks which still have links like `{{#include}}` in
+/// them, since they mean keeper ran before mdbook's `links` preprocessor
+/// expanded them, and the blocks will fail to compile.
+fn warn_about_unexpanded_links(tests: &[Test]) {
+    if let Some(test) = tests.iter().find(|test| {
+        test.text
+            .iter()
+            .any(|line| INCLUDE_LINKS.iter().any(|link| line.contains(link)))
+    }) {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {}:{} ({}) still has an `{{{{#include}}}}` link in it, so keeper ran \
+                 before mdbook expanded it. Add `after = [\"links\"]` to [preprocessor.keeper].",
+                test.file, test.line, test.name
+            )
+            .yellow()
+        );
+    }
+}
+
+/// Warns about chapters with a `skeptic-template` block without a name,
+/// since keeper doesn't apply those templates, so blocks written to be
+/// wrapped in one are likely to fail.
+fn warn_about_old_templates(tests: &[Test], old_templates: &HashMap<String, String>) {
+    let mut files = old_templates.keys().collect::<Vec<_>>();
+    files.sort();
+    for file in files {
+        let count = tests.iter().filter(|test| &test.file == file).count();
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {} defines a `skeptic-template` without a name, but keeper doesn't \
+                 apply it, so its {} examples are tested without it.",
+                file, count
+            )
+            .yellow()
+        );
+    }
+}
+
+/// Checks every code block (including `ignore`d ones) for the patterns
+/// in `forbid_patterns`. Depending on `forbid_level`, a match is either
+/// an error, or just a warning.
+fn check_forbidden_patterns(tests: &[Test], config: &KeeperConfig) -> Result<(), Error> {
+    let mut matches = Vec::new();
+    for test in tests {
+        // Prose in comments (or a string) mentioning a pattern is fine.
+        let code =
+            strip_comments_and_literals(&create_test_input(&test.text, DEFAULT_WRAPPER_TEMPLATE));
+        for pattern in &config.forbid_patterns {
+            if pattern.is_match(&code) {
+                matches.push(format!(
+                    "{}:{} ({}) matches the forbidden pattern `{}`",
+                    test.file, test.line, test.name, pattern
+                ));
+            }
+        }
+    }
+
+    if matches.is_empty() {
+        return Ok(());
+    }
+    match config.forbid_level {
+        Level::Deny => Err(Error::msg(format!(
+            "Code blocks use forbidden patterns:\n{}",
+            matches.join("\n")
+        ))),
+        Level::Warn => {
+            for message in matches {
+                eprintln!("{}", format!("Warning: {}", message).yellow());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// What happens when a code block has a problem which doesn't stop it
+/// compiling, like matching one of the `forbid_patterns`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Level {
+    /// It's an error: the build stops (for `forbid_patterns`),
+    /// or the test fails (for compile budgets).
+    #[default]
+    Deny,
+    /// A warning is printed.
+    Warn,
+}
+
+/// The kind of crate blocks which would be run are compiled as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CrateType {
+    /// A binary, which is then run.
+    #[default]
+    Bin,
+    /// A library, which is only checked, and not run.
+    Lib,
+}
+
+/// How much of a backtrace examples print if they panic, through
+/// `RUST_BACKTRACE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Backtrace {
+    /// No backtrace (`RUST_BACKTRACE=0`).
+    Off,
+    /// The usual, abbreviated backtrace (`RUST_BACKTRACE=1`).
+    #[default]
+    Short,
+    /// Every frame (`RUST_BACKTRACE=full`).
+    Full,
+}
+
+impl Backtrace {
+    /// The value of `RUST_BACKTRACE` for this setting.
+    pub(crate) fn env_value(self) -> &'static str {
+        match self {
+            Backtrace::Off => "0",
+            Backtrace::Short => "1",
+            Backtrace::Full => "full",
+        }
+    }
+}
+
+/// A level rustc can give a lint, for `dependency_lints`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+    Forbid,
+}
+
+impl LintLevel {
+    /// The rustc option which sets a lint to this level.
+    fn flag(self) -> &'static str {
+        match self {
+            LintLevel::Allow => "-A",
+            LintLevel::Warn => "-W",
+            LintLevel::Deny => "-D",
+            LintLevel::Forbid => "-F",
+        }
+    }
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -67,285 +452,2698 @@ struct KeeperConfigParser {
     /// of testing. If it's not specified, it's a folder
     /// inside build. If it doesn't exist, we create it.
     #[serde(default)]
-    test_dir: Option<String>,
+    test_dir: Option<PathBuf>,
 
     /// If you're building this book in the repo for a
     /// real binary/library; this should point to the target
     /// dir for that binary/library.
     #[serde(default)]
-    target_dir: Option<String>,
+    target_dir: Option<PathBuf>,
 
     /// This is the path of a folder that should contain
     /// a `Cargo.toml`. If there is one there, you should
     /// assume a `Cargo.lock` will be created in the same
     /// place if it doesn't already exist.
     #[serde(default)]
-    manifest_dir: Option<String>,
+    manifest_dir: Option<PathBuf>,
+
+    /// This allows you to specify if the manifest dir is
+    /// of a cargo workspace. If set to true, `--workspace`
+    /// will be passed to the invocation of `cargo build`.
+    #[serde(default)]
+    is_workspace: Option<bool>,
+
+    /// This allows you to specify the features you want to
+    /// invoke `cargo build` with. If you set this to
+    /// `["first", "second"], it  causes `--features
+    /// first,second` to be added to the invocation of
+    /// `cargo build`.
+    #[serde(default)]
+    build_features: Vec<String>,
+
+    /// Whether to show terminal colours.
+    #[serde(default)]
+    terminal_colors: Option<bool>,
+
+    /// If set to true, code blocks which look like fragments
+    /// of a larger program (e.g. `.map(|x| x + 1)`) are skipped,
+    /// rather than being reported as compile failures.
+    #[serde(default)]
+    skip_fragments: Option<bool>,
+
+    /// When the packages in `manifest_dir` use different editions,
+    /// this decides which one examples are compiled with. One of
+    /// `max` (the default), `min` or `book-crate` (the edition of
+    /// the package in `manifest_dir` itself).
+    #[serde(default)]
+    edition_strategy: Option<EditionStrategy>,
+
+    /// The edition to compile examples with (e.g. `2021`). This
+    /// takes precedence over `edition_strategy`, and is the only way
+    /// to pick an edition if there's no `manifest_dir`.
+    #[serde(default)]
+    edition: Option<String>,
+
+    /// If set to true, examples are compiled incrementally,
+    /// keeping rustc's incremental state in `test_dir/incremental`.
+    #[serde(default)]
+    incremental: Option<bool>,
+
+    /// The sanitizer to compile and run examples with (e.g.
+    /// `address` or `leak`). This requires a nightly compiler.
+    #[serde(default)]
+    sanitizer: Option<String>,
+
+    /// The target triple to build the project and compile examples
+    /// for. If it's not specified, the host's target is used.
+    #[serde(default)]
+    target: Option<String>,
+
+    /// If set to true, the definitions from each code block in a
+    /// chapter are carried forward as a hidden prelude to the blocks
+    /// after it in the same chapter.
+    #[serde(default)]
+    accumulate: Option<bool>,
+
+    /// If set to true, `#[ignore]`d tests in code blocks which use
+    /// the test harness are run too (like `cargo test -- --include-ignored`).
+    #[serde(default)]
+    run_ignored_tests: Option<bool>,
+
+    /// If set to true, `no_run` code blocks are compiled and linked
+    /// into a binary (which isn't run), rather than just checked.
+    /// This catches linker errors, but means they need a `main`.
+    #[serde(default)]
+    no_run_links: Option<bool>,
+
+    /// The source file each code block is turned into, with `{code}`
+    /// standing in for the block's code. This can add attributes like
+    /// `#![no_std]`, or wrap the code in a module. By default, the
+    /// file is just the block's code.
+    #[serde(default)]
+    wrapper_template: Option<String>,
+
+    /// If set to true, `cargo build` is passed `--locked`, so the
+    /// build fails rather than changing `Cargo.lock`.
+    #[serde(default)]
+    locked: Option<bool>,
+
+    /// If set to true, `cargo build` is passed `--offline`, so it
+    /// doesn't access the network.
+    #[serde(default)]
+    offline: Option<bool>,
+
+    /// If set to true, the results say which tests were reused
+    /// from the cache, and which were built, along with the path
+    /// of their file in `test_dir`.
+    #[serde(default)]
+    verbose: Option<bool>,
+
+    /// A regex which the stderr of every `should_panic` example must
+    /// match, so that books can make sure each panic they demonstrate
+    /// has a message in the expected format.
+    #[serde(default)]
+    should_panic_pattern: Option<String>,
+
+    /// If set to true, keeper behaves as much like rustdoc (and so
+    /// `mdbook test`) as it can. See the README for what this changes.
+    #[serde(default)]
+    rustdoc_compat: Option<bool>,
+
+    /// Regexes which no code block may match, such as `unsafe`
+    /// or `\.unwrap\(\)`. Blocks marked `ignore` are checked too.
+    #[serde(default)]
+    forbid_patterns: Vec<String>,
+
+    /// Whether a block matching one of the `forbid_patterns` is
+    /// an error (`deny`, the default) or a warning (`warn`).
+    #[serde(default)]
+    forbid_level: Option<Level>,
+
+    /// If set to true, every example is treated as `no_std`: they are
+    /// type-checked, but not linked or run. Blocks containing
+    /// `#![no_std]` are always treated this way.
+    #[serde(default)]
+    no_std: Option<bool>,
+
+    /// If set to true, each test's files are put in a subdirectory of
+    /// `test_dir` named after the start of its hash, rather than all
+    /// being in `test_dir` itself.
+    #[serde(default)]
+    shard_cache: Option<bool>,
+
+    /// A directory whose contents are copied into a fresh directory
+    /// for each example to run in, for examples which expect some
+    /// files to exist.
+    #[serde(default)]
+    fixture_dir: Option<PathBuf>,
+
+    /// If set to true, a line starting with `KEEPER_SUMMARY` and
+    /// followed by counts of the results as JSON is printed at the end,
+    /// for tools which wrap `mdbook build`.
+    #[serde(default)]
+    machine_summary: Option<bool>,
+
+    /// If set to false, `manifest_dir` isn't built with cargo before
+    /// testing, and `target_dir` must already contain an up-to-date
+    /// build. Defaults to true.
+    #[serde(default)]
+    build_project: Option<bool>,
+
+    /// If set to true, and the standard library for `target` isn't
+    /// installed, no tests are run (with a warning) rather than it
+    /// being an error.
+    #[serde(default)]
+    skip_if_target_missing: Option<bool>,
+
+    /// The `Cargo.lock` which decides the version of each dependency
+    /// examples use, if it isn't the one cargo finds for `manifest_dir`.
+    #[serde(default)]
+    lockfile: Option<PathBuf>,
+
+    /// How long (in milliseconds) each example may take to compile.
+    /// Blocks can set their own budget with `//@ max_compile_ms: <ms>`.
+    #[serde(default)]
+    max_compile_ms: Option<u64>,
+
+    /// Whether an example going over its compile budget fails
+    /// (`deny`, the default) or just gets a warning (`warn`).
+    #[serde(default)]
+    compile_budget_level: Option<Level>,
+
+    /// A directory to also write the source of every test to, named
+    /// after the test, to see exactly what is given to rustc. A relative
+    /// path is from the book's root.
+    #[serde(default)]
+    dump_tests_dir: Option<PathBuf>,
+
+    /// Exit codes (besides 0) which examples may finish with and
+    /// still pass, for books whose examples use exit codes to signal
+    /// documented conditions.
+    #[serde(default)]
+    allowed_exit_codes: Vec<i32>,
+
+    /// The level of particular lints when `cargo build` builds the
+    /// project in `manifest_dir` and its dependencies, like
+    /// `{ deprecated = "warn", unused = "allow" }`.
+    #[serde(default)]
+    dependency_lints: HashMap<String, LintLevel>,
+
+    /// If set to true, a cached test is only trusted if its file still
+    /// holds the code keeper would write for it; otherwise it's run again.
+    #[serde(default)]
+    verify_cache: Option<bool>,
+
+    /// Attributes every block in the chapters matching a glob gets, like
+    /// `{ "mistakes/*.md" = ["compile_fail"] }`. Blocks which give any of
+    /// these attributes themselves keep their own.
+    #[serde(default)]
+    chapter_attributes: HashMap<String, Vec<String>>,
+
+    /// Set as `LC_ALL` when examples are run, so their output doesn't
+    /// depend on the locale of the machine building the book.
+    #[serde(default)]
+    locale: Option<String>,
+
+    /// Set as `TZ` when examples are run, so their output doesn't
+    /// depend on the timezone of the machine building the book.
+    #[serde(default)]
+    timezone: Option<String>,
+
+    /// If set to true, only the tests which failed last time (or whose
+    /// code changed since they passed) are run.
+    #[serde(default)]
+    only_failed: Option<bool>,
+
+    /// Blocks ignored for one of these reasons, like
+    /// `ignore (requires-network)`, are run anyway.
+    #[serde(default)]
+    run_ignored_reasons: Vec<String>,
+
+    /// If set, examples are compiled with `--error-format=json`, and
+    /// every diagnostic is written to this file, one JSON object per
+    /// line, along with the test and chapter line it came from. A
+    /// relative path is from the book's root.
+    #[serde(default)]
+    diagnostics_path: Option<PathBuf>,
+
+    /// If set to true, a note of each test's result is added to the
+    /// book after its code block.
+    #[serde(default)]
+    annotate_blocks: Option<bool>,
+
+    /// How much of a backtrace examples print if they panic:
+    /// `"off"`, `"short"` or `"full"`. By default, `RUST_BACKTRACE`
+    /// is passed on, or `"short"` is used if it isn't set.
+    #[serde(default)]
+    backtrace: Option<Backtrace>,
+
+    /// Given to examples as `KEEPER_SEED` when they're run, so examples
+    /// which use randomness can seed their RNG with it.
+    #[serde(default)]
+    seed: Option<u64>,
+
+    /// Every block (which doesn't give its own edition) is compiled once
+    /// for each of these editions, as a separate test.
+    #[serde(default)]
+    test_editions: Vec<String>,
+
+    /// If set to true, examples can use the project's dev-dependencies
+    /// as well as its dependencies. The project's tests are built too,
+    /// since that's what builds its dev-dependencies.
+    #[serde(default)]
+    include_dev_dependencies: Option<bool>,
+
+    /// If set to true, nothing is written into `test_dir`. Tests are
+    /// compiled in a temporary directory instead, which is removed
+    /// afterwards, so nothing is cached between runs.
+    #[serde(default)]
+    read_only_cache: Option<bool>,
+
+    /// A program to run rustc through when compiling examples, like
+    /// `"sccache"`, instead of `RUSTC_WRAPPER` and
+    /// `RUSTC_WORKSPACE_WRAPPER`. An empty string means no wrapper.
+    #[serde(default)]
+    rustc_wrapper: Option<String>,
+
+    /// If set to true, blocks are only type-checked, as if they were
+    /// `no_run`. `should_panic` blocks are still run, since panicking
+    /// is what they're checking.
+    #[serde(default)]
+    check_only: Option<bool>,
+
+    /// If set to true, the configured edition (from `edition`, or from
+    /// `manifest_dir`) is used even for blocks which give their own, like
+    /// `edition2018`.
+    #[serde(default)]
+    edition_override: Option<bool>,
+
+    /// If set to true, the log of a failing test says when it printed
+    /// nothing to stdout or stderr, rather than leaving that stream out.
+    #[serde(default)]
+    show_empty_streams: Option<bool>,
+
+    /// `"lib"` checks blocks as libraries, rather than building and
+    /// running them as binaries (`"bin"`, the default). `should_panic`
+    /// blocks, and blocks expecting an exit code, are still run.
+    #[serde(default)]
+    default_crate_type: Option<CrateType>,
+
+    /// The kind of crate `compile_fail` blocks are compiled as, if it's
+    /// different to `default_crate_type`.
+    #[serde(default)]
+    compile_fail_crate_type: Option<CrateType>,
+
+    /// If set to true, a `should_panic` block passes if it fails in any
+    /// way when run (like exiting with code 1), not only if it panics.
+    #[serde(default)]
+    should_panic_any_failure: Option<bool>,
+
+    /// How many characters of each test's hash its files are named
+    /// with, to keep paths short. By default, the whole hash is used.
+    #[serde(default)]
+    hash_length: Option<usize>,
+
+    /// Blocks with more lines of code than this (counting hidden lines)
+    /// are only type-checked, rather than built and run.
+    #[serde(default)]
+    run_size_limit_lines: Option<usize>,
+
+    /// How many times each example which is run is run, to see how long
+    /// it takes. Defaults to 1.
+    #[serde(default)]
+    run_iterations: Option<u32>,
+
+    /// With `rustdoc_compat`, how `main` is written for examples without
+    /// one: `"unit"`, `"result"`, or a table giving its `return_type` and
+    /// the `uses` to import.
+    #[serde(default)]
+    main_wrapper: Option<MainWrapper>,
+
+    /// A markdown file outside the book (like the project's README) whose
+    /// code blocks are tested along with the book's.
+    #[serde(default)]
+    readme_path: Option<PathBuf>,
+
+    /// If set to true, everything keeper cached in `test_dir` is removed
+    /// before testing, so every example is tested again.
+    #[serde(default)]
+    clear_cache: Option<bool>,
+
+    /// A JSON file mapping crate names to the libraries examples should
+    /// use, for projects built by something other than cargo. It's used
+    /// instead of `manifest_dir`.
+    #[serde(default)]
+    deps_manifest: Option<PathBuf>,
+
+    /// A JSON file to write the version of each crate examples were
+    /// linked against to, to record what the book was tested with.
+    #[serde(default)]
+    export_versions_path: Option<PathBuf>,
+
+    /// If set to true, nothing is compiled: instead, keeper lists every
+    /// code block, and whether it would be tested (and if not, why not).
+    #[serde(default)]
+    audit: Option<bool>,
+}
+
+/// The name of the file in the book's root whose options are merged over
+/// `[preprocessor.keeper]`, so they can be kept out of `book.toml`.
+const OVERRIDE_FILE: &str = ".keeper.toml";
+
+/// The options in the book's `.keeper.toml`, if it has one.
+fn read_override_file(root: &Path) -> Result<Option<Table>, Error> {
+    let path = root.join(OVERRIDE_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let overrides = toml::from_str(&std::fs::read_to_string(&path)?)
+        .map_err(|e| Error::msg(format!("couldn't read {}: {}", path.display(), e)))?;
+    Ok(Some(overrides))
+}
+
+/// Merges the profile called `profile` (from the `profiles` table) over
+/// the rest of the config. Each key set in the profile replaces the
+/// same key in the base config.
+fn apply_profile(config: &Table, profile: Option<&str>) -> Result<Table, Error> {
+    let mut merged = config.clone();
+    let profiles = merged.remove("profiles");
+
+    if let Some(profile) = profile {
+        let overrides = profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(profile))
+            .and_then(|overrides| overrides.as_table())
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "KEEPER_PROFILE is `{}`, but there is no \
+                     [preprocessor.keeper.profiles.{}] table.",
+                    profile, profile
+                ))
+            })?;
+
+        for (key, value) in overrides {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Lets a `cargo build` running on another thread be stopped, once it
+/// turns out that no test needs the project.
+#[derive(Default)]
+struct BuildCancellation {
+    state: Mutex<CancellationState>,
+}
+
+#[derive(Default)]
+struct CancellationState {
+    cancelled: bool,
+    /// The `cargo build` which is running, if any.
+    child: Option<Child>,
+}
+
+impl BuildCancellation {
+    fn lock(&self) -> MutexGuard<'_, CancellationState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn cancel(&self) {
+        let mut state = self.lock();
+        state.cancelled = true;
+        if let Some(mut child) = state.child.take() {
+            // cargo may have just finished, in which case there's nothing to kill.
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// The configuration keeper runs with, from `[preprocessor.keeper]`.
+#[derive(Debug)]
+pub struct KeeperConfig {
+    test_dir: PathBuf,
+    target_dir: PathBuf,
+    manifest_dir: Option<PathBuf>,
+    is_workspace: bool,
+    build_features: Vec<String>,
+    terminal_colors: bool,
+    externs: Vec<String>,
+    skip_fragments: bool,
+    edition_strategy: EditionStrategy,
+    edition: Option<String>,
+    incremental: bool,
+    sanitizer: Option<String>,
+    target: Option<String>,
+    accumulate: bool,
+    run_ignored_tests: bool,
+    no_run_links: bool,
+    wrapper_template: String,
+    locked: bool,
+    offline: bool,
+    verbose: bool,
+    should_panic_pattern: Option<Regex>,
+    rustdoc_compat: bool,
+    forbid_patterns: Vec<Regex>,
+    forbid_level: Level,
+    no_std: bool,
+    shard_cache: bool,
+    fixture_dir: Option<PathBuf>,
+    machine_summary: bool,
+    build_project: bool,
+    skip_if_target_missing: bool,
+    lockfile: Option<PathBuf>,
+    max_compile_ms: Option<u64>,
+    compile_budget_level: Level,
+    dump_tests_dir: Option<PathBuf>,
+    allowed_exit_codes: Vec<i32>,
+    dependency_lints: HashMap<String, LintLevel>,
+    verify_cache: bool,
+    chapter_attributes: Vec<(glob::Pattern, Vec<String>)>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    /// With `only_failed`, the code hash of each test which passed last
+    /// time, keyed by name. Otherwise, empty.
+    previously_passed: HashMap<String, String>,
+    run_ignored_reasons: Vec<String>,
+    diagnostics_path: Option<PathBuf>,
+    annotate_blocks: bool,
+    /// Only set when configured. Otherwise, examples get `RUST_BACKTRACE`
+    /// from the environment, or [`Backtrace::default`] if it isn't set.
+    backtrace: Option<Backtrace>,
+    seed: Option<u64>,
+    test_editions: Vec<String>,
+    include_dev_dependencies: bool,
+    rustc_wrapper: Option<String>,
+    check_only: bool,
+    edition_override: bool,
+    show_empty_streams: bool,
+    default_crate_type: CrateType,
+    compile_fail_crate_type: Option<CrateType>,
+    should_panic_any_failure: bool,
+    hash_length: Option<usize>,
+    run_size_limit_lines: Option<usize>,
+    run_iterations: u32,
+    readme_path: Option<PathBuf>,
+    /// What tests from `readme_path` give as their file: its path from
+    /// the book's root, or else just its file name.
+    readme_name: Option<PathBuf>,
+    main_wrapper: Option<MainWrapper>,
+    /// The libraries listed in `deps_manifest`, keyed by crate name.
+    prebuilt_deps: Option<BTreeMap<String, PathBuf>>,
+    export_versions_path: Option<PathBuf>,
+    audit: bool,
+    /// Whether to clear the cache before the tests are found.
+    clear_cache: bool,
+    /// With `read_only_cache`, the temporary directory used as `test_dir`.
+    /// It's removed when the config is dropped.
+    temporary_test_dir: Option<TempDir>,
+    /// What the book is called, to tell its cache apart from other books'.
+    book_name: String,
+    /// The edition found from `manifest_dir`, once it's been looked for
+    /// (see [`KeeperConfig::project_edition`]).
+    project_edition: OnceLock<Result<Option<&'static str>, String>>,
+}
+
+/// Whether `test` would be run, but could just be checked instead: it's
+/// run for its output, not to see if it panics or what it exits with.
+fn can_be_checked_instead(test: &Test) -> bool {
+    !test.no_run && !test.should_panic && test.exit_code.is_none()
+}
+
+/// The shortest `hash_length` which doesn't risk two tests getting the
+/// same file. Each character of the hash is 6 bits.
+const MIN_HASH_LENGTH: usize = 12;
+
+/// The attributes `chapter_attributes` can give blocks.
+const CHAPTER_ATTRIBUTES: [&str; 4] = ["compile_fail", "should_panic", "no_run", "ignore"];
+
+/// Builds a [`KeeperConfig`] in code, for tools which use keeper as a
+/// library, rather than from a `[preprocessor.keeper]` table. Options
+/// which aren't set have the same defaults they do in `book.toml` (see
+/// the README for what each one does). `KEEPER_PROFILE` isn't used.
+///
+/// The most common options have their own methods; any other can be set
+/// with [`KeeperConfigBuilder::option`], as it would be in `book.toml`.
+#[derive(Debug, Default)]
+pub struct KeeperConfigBuilder {
+    options: Table,
+}
+
+/// A path, as it would be given in `book.toml`.
+fn path_value(path: impl Into<PathBuf>) -> toml::Value {
+    toml::Value::String(path.into().to_string_lossy().into_owned())
+}
+
+fn strings_value<S: Into<String>>(strings: impl IntoIterator<Item = S>) -> toml::Value {
+    toml::Value::from(strings.into_iter().map(Into::into).collect::<Vec<String>>())
+}
+
+impl KeeperConfigBuilder {
+    pub fn new() -> KeeperConfigBuilder {
+        KeeperConfigBuilder::default()
+    }
+
+    /// Sets the option called `key` (like `run_iterations`) to `value`,
+    /// replacing anything it was set to before. Options are checked when
+    /// the config is built, as they would be in `book.toml`.
+    pub fn option(mut self, key: impl Into<String>, value: impl Into<toml::Value>) -> Self {
+        self.options.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn externs<S: Into<String>>(self, externs: impl IntoIterator<Item = S>) -> Self {
+        self.option("externs", strings_value(externs))
+    }
+
+    pub fn test_dir(self, test_dir: impl Into<PathBuf>) -> Self {
+        self.option("test_dir", path_value(test_dir))
+    }
+
+    pub fn target_dir(self, target_dir: impl Into<PathBuf>) -> Self {
+        self.option("target_dir", path_value(target_dir))
+    }
+
+    pub fn manifest_dir(self, manifest_dir: impl Into<PathBuf>) -> Self {
+        self.option("manifest_dir", path_value(manifest_dir))
+    }
+
+    pub fn is_workspace(self, is_workspace: bool) -> Self {
+        self.option("is_workspace", is_workspace)
+    }
+
+    pub fn build_features<S: Into<String>>(self, features: impl IntoIterator<Item = S>) -> Self {
+        self.option("build_features", strings_value(features))
+    }
+
+    pub fn lockfile(self, lockfile: impl Into<PathBuf>) -> Self {
+        self.option("lockfile", path_value(lockfile))
+    }
+
+    pub fn locked(self, locked: bool) -> Self {
+        self.option("locked", locked)
+    }
+
+    pub fn offline(self, offline: bool) -> Self {
+        self.option("offline", offline)
+    }
+
+    pub fn edition(self, edition: impl Into<String>) -> Self {
+        self.option("edition", edition.into())
+    }
+
+    pub fn target(self, target: impl Into<String>) -> Self {
+        self.option("target", target.into())
+    }
+
+    pub fn terminal_colors(self, terminal_colors: bool) -> Self {
+        self.option("terminal_colors", terminal_colors)
+    }
+
+    pub fn skip_fragments(self, skip_fragments: bool) -> Self {
+        self.option("skip_fragments", skip_fragments)
+    }
+
+    pub fn rustdoc_compat(self, rustdoc_compat: bool) -> Self {
+        self.option("rustdoc_compat", rustdoc_compat)
+    }
+
+    pub fn fixture_dir(self, fixture_dir: impl Into<PathBuf>) -> Self {
+        self.option("fixture_dir", path_value(fixture_dir))
+    }
+
+    pub fn verbose(self, verbose: bool) -> Self {
+        self.option("verbose", verbose)
+    }
+
+    pub fn seed(self, seed: u64) -> Self {
+        // TOML integers are signed, so (as in `book.toml`) a seed too big
+        // for one is an error when the config is built.
+        self.option("seed", seed as i64)
+    }
+
+    pub fn test_editions<S: Into<String>>(self, editions: impl IntoIterator<Item = S>) -> Self {
+        self.option("test_editions", strings_value(editions))
+    }
+
+    pub fn read_only_cache(self, read_only_cache: bool) -> Self {
+        self.option("read_only_cache", read_only_cache)
+    }
+
+    pub fn rustc_wrapper(self, rustc_wrapper: impl Into<String>) -> Self {
+        self.option("rustc_wrapper", rustc_wrapper.into())
+    }
+
+    pub fn deps_manifest(self, deps_manifest: impl Into<PathBuf>) -> Self {
+        self.option("deps_manifest", path_value(deps_manifest))
+    }
+
+    /// The config, for the book whose root is `root`.
+    pub fn build(self, root: &Path) -> Result<KeeperConfig, Error> {
+        let keeper_config = toml::Value::Table(self.options).try_into()?;
+        KeeperConfig::from_parser(keeper_config, root)
+    }
+}
+
+impl KeeperConfig {
+    pub fn new(
+        preprocessor_config: PreprocessorConfig,
+        root: &Path,
+    ) -> Result<KeeperConfig, Error> {
+        let profile = std::env::var("KEEPER_PROFILE").ok();
+        // Options in `.keeper.toml` replace those in `[preprocessor.keeper]`.
+        let preprocessor_config = match read_override_file(root)? {
+            Some(overrides) => {
+                let mut merged = preprocessor_config.cloned().unwrap_or_default();
+                merged.extend(overrides);
+                Some(merged)
+            }
+            None => preprocessor_config.cloned(),
+        };
+        let keeper_config: KeeperConfigParser = match &preprocessor_config {
+            Some(config) => {
+                let config = apply_profile(config, profile.as_deref())?;
+                // Converting the value directly (rather than through a string)
+                // means tables, like `dependency_lints`, can come before other keys.
+                toml::Value::Table(config).try_into()?
+            }
+            None => KeeperConfigParser::default(),
+        };
+        KeeperConfig::from_parser(keeper_config, root)
+    }
+
+    fn from_parser(keeper_config: KeeperConfigParser, root: &Path) -> Result<KeeperConfig, Error> {
+        let base_dir = root.to_path_buf();
+        let temporary_test_dir = if keeper_config.read_only_cache.unwrap_or(false) {
+            Some(tempfile::Builder::new().prefix("keeper").tempdir()?)
+        } else {
+            None
+        };
+        let test_dir = match &temporary_test_dir {
+            Some(dir) => dir.path().to_path_buf(),
+            None => keeper_config.test_dir.unwrap_or_else(|| {
+                let mut build_dir = base_dir;
+                build_dir.push("doctest_cache");
+                build_dir
+            }),
+        };
+
+        let target_dir = keeper_config.target_dir.unwrap_or_else(|| {
+            let mut target_dir = test_dir.clone();
+            target_dir.push("target");
+            target_dir
+        });
+
+        let manifest_dir = keeper_config.manifest_dir;
+        let lockfile = keeper_config.lockfile;
+        if let Some(lockfile) = &lockfile {
+            if !lockfile.is_file() {
+                return Err(Error::msg(format!(
+                    "lockfile `{}` does not exist",
+                    lockfile.display()
+                )));
+            }
+        }
+
+        let prebuilt_deps = match &keeper_config.deps_manifest {
+            Some(_) if manifest_dir.is_some() => {
+                return Err(Error::msg(
+                    "deps_manifest and manifest_dir can't both be set",
+                ))
+            }
+            Some(deps_manifest) => {
+                let deps = read_deps_manifest(deps_manifest).map_err(|e| {
+                    Error::msg(format!(
+                        "couldn't read deps_manifest `{}`: {}",
+                        deps_manifest.display(),
+                        e
+                    ))
+                })?;
+                if let Some((name, rlib)) = deps.iter().find(|(_, rlib)| !rlib.is_file()) {
+                    return Err(Error::msg(format!(
+                        "deps_manifest gives `{}` for `{}`, but it doesn't exist",
+                        rlib.display(),
+                        name
+                    )));
+                }
+                Some(deps)
+            }
+            None => None,
+        };
+
+        // Only cargo says which version of each crate was used.
+        if keeper_config.export_versions_path.is_some() && manifest_dir.is_none() {
+            return Err(Error::msg(
+                "export_versions_path needs manifest_dir to be set",
+            ));
+        }
+
+        let clear_cache = keeper_config.clear_cache.unwrap_or(false);
+        // A cleared cache has nothing which passed last time.
+        let previously_passed = if keeper_config.only_failed.unwrap_or(false) && !clear_cache {
+            cache::read_passed(&test_dir)
+        } else {
+            HashMap::new()
+        };
+
+        let mut chapter_attributes = Vec::new();
+        for (chapters, attributes) in keeper_config.chapter_attributes {
+            let pattern = glob::Pattern::new(&chapters).map_err(|e| {
+                Error::msg(format!(
+                    "chapter_attributes: bad glob `{}`: {}",
+                    chapters, e
+                ))
+            })?;
+            if let Some(attribute) = attributes
+                .iter()
+                .find(|a| !CHAPTER_ATTRIBUTES.contains(&a.as_str()))
+            {
+                return Err(Error::msg(format!(
+                    "chapter_attributes: `{}` can't be given to a chapter (expected one of {})",
+                    attribute,
+                    CHAPTER_ATTRIBUTES.join(", ")
+                )));
+            }
+            chapter_attributes.push((pattern, attributes));
+        }
+
+        let readme_path = keeper_config.readme_path;
+        if let Some(readme_path) = &readme_path {
+            if !readme_path.is_file() {
+                return Err(Error::msg(format!(
+                    "readme_path `{}` does not exist",
+                    readme_path.display()
+                )));
+            }
+        }
+        // The full path would differ between machines, and so would the
+        // names (and hashes) of its tests.
+        let readme_name = readme_path.as_ref().map(|readme_path| {
+            let relative = readme_path.canonicalize().ok().and_then(|readme_path| {
+                let root = root.canonicalize().ok()?;
+                Some(readme_path.strip_prefix(root).ok()?.to_path_buf())
+            });
+            relative.unwrap_or_else(|| readme_path.file_name().unwrap_or_default().into())
+        });
+
+        let fixture_dir = keeper_config.fixture_dir;
+        if let Some(fixture_dir) = &fixture_dir {
+            if !fixture_dir.is_dir() {
+                return Err(Error::msg(format!(
+                    "fixture_dir `{}` is not a directory",
+                    fixture_dir.display()
+                )));
+            }
+        }
+        let is_workspace = keeper_config.is_workspace.unwrap_or(false);
+
+        let hash_length = keeper_config.hash_length;
+        match hash_length {
+            Some(0) => return Err(Error::msg("hash_length must be at least 1")),
+            Some(length) if length < MIN_HASH_LENGTH => eprintln!(
+                "{}",
+                format!(
+                    "Warning: with a hash_length of {}, different tests may be given the same \
+                     file, and so be reported as cached without being run. Use at least {}.",
+                    length, MIN_HASH_LENGTH
+                )
+                .yellow()
+            ),
+            _ => {}
+        }
+
+        let terminal_colors = keeper_config
+            .terminal_colors
+            .unwrap_or_else(|| atty::is(Stream::Stderr));
+
+        set_override(terminal_colors);
+
+        let rustdoc_compat = keeper_config.rustdoc_compat.unwrap_or(false);
+
+        let should_panic_pattern = keeper_config
+            .should_panic_pattern
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| {
+                Error::msg(format!(
+                    "`should_panic_pattern` is not a valid regex: {}",
+                    e
+                ))
+            })?;
+
+        let forbid_patterns = keeper_config
+            .forbid_patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::msg(format!("`forbid_patterns` has an invalid regex: {}", e)))?;
+
+        let wrapper_template = keeper_config
+            .wrapper_template
+            .unwrap_or_else(|| DEFAULT_WRAPPER_TEMPLATE.to_string());
+        if !wrapper_template.contains("{code}") {
+            return Err(Error::msg(
+                "`wrapper_template` must contain `{code}`, where each code block goes.",
+            ));
+        }
+
+        Ok(KeeperConfig {
+            test_dir,
+            target_dir,
+            manifest_dir,
+            is_workspace,
+            build_features: keeper_config.build_features,
+            terminal_colors,
+            externs: keeper_config.externs,
+            skip_fragments: keeper_config.skip_fragments.unwrap_or(false),
+            edition_strategy: keeper_config.edition_strategy.unwrap_or(if rustdoc_compat {
+                EditionStrategy::BookCrate
+            } else {
+                EditionStrategy::default()
+            }),
+            edition: keeper_config.edition,
+            incremental: keeper_config.incremental.unwrap_or(false),
+            sanitizer: keeper_config.sanitizer,
+            target: keeper_config.target,
+            accumulate: keeper_config.accumulate.unwrap_or(false),
+            run_ignored_tests: keeper_config.run_ignored_tests.unwrap_or(false),
+            no_run_links: keeper_config.no_run_links.unwrap_or(false),
+            wrapper_template,
+            locked: keeper_config.locked.unwrap_or(false),
+            offline: keeper_config.offline.unwrap_or(false),
+            verbose: keeper_config.verbose.unwrap_or(false),
+            should_panic_pattern,
+            rustdoc_compat,
+            forbid_patterns,
+            forbid_level: keeper_config.forbid_level.unwrap_or_default(),
+            no_std: keeper_config.no_std.unwrap_or(false),
+            shard_cache: keeper_config.shard_cache.unwrap_or(false),
+            fixture_dir,
+            machine_summary: keeper_config.machine_summary.unwrap_or(false),
+            build_project: keeper_config.build_project.unwrap_or(true),
+            skip_if_target_missing: keeper_config.skip_if_target_missing.unwrap_or(false),
+            lockfile,
+            max_compile_ms: keeper_config.max_compile_ms,
+            compile_budget_level: keeper_config.compile_budget_level.unwrap_or_default(),
+            dump_tests_dir: keeper_config.dump_tests_dir.map(|dir| root.join(dir)),
+            allowed_exit_codes: keeper_config.allowed_exit_codes,
+            dependency_lints: keeper_config.dependency_lints,
+            verify_cache: keeper_config.verify_cache.unwrap_or(false),
+            chapter_attributes,
+            locale: keeper_config.locale,
+            timezone: keeper_config.timezone,
+            previously_passed,
+            run_ignored_reasons: keeper_config.run_ignored_reasons,
+            diagnostics_path: keeper_config.diagnostics_path.map(|path| root.join(path)),
+            annotate_blocks: keeper_config.annotate_blocks.unwrap_or(false),
+            backtrace: keeper_config.backtrace,
+            seed: keeper_config.seed,
+            test_editions: keeper_config.test_editions,
+            include_dev_dependencies: keeper_config.include_dev_dependencies.unwrap_or(false),
+            rustc_wrapper: keeper_config.rustc_wrapper,
+            check_only: keeper_config.check_only.unwrap_or(false),
+            edition_override: keeper_config.edition_override.unwrap_or(false),
+            show_empty_streams: keeper_config.show_empty_streams.unwrap_or(false),
+            default_crate_type: keeper_config.default_crate_type.unwrap_or_default(),
+            compile_fail_crate_type: keeper_config.compile_fail_crate_type,
+            should_panic_any_failure: keeper_config.should_panic_any_failure.unwrap_or(false),
+            hash_length,
+            run_size_limit_lines: keeper_config.run_size_limit_lines,
+            run_iterations: keeper_config.run_iterations.unwrap_or(1),
+            readme_path,
+            readme_name,
+            main_wrapper: keeper_config.main_wrapper,
+            prebuilt_deps,
+            export_versions_path: keeper_config.export_versions_path,
+            audit: keeper_config.audit.unwrap_or(false),
+            clear_cache,
+            temporary_test_dir,
+            book_name: book_name(root),
+            project_edition: OnceLock::new(),
+        })
+    }
+
+    /// The edition `edition_strategy` picks from the packages in
+    /// `manifest_dir`, if any has an edition keeper knows. cargo is only
+    /// asked the first time.
+    fn project_edition(&self, manifest_dir: &Path) -> Result<Option<&'static str>, Error> {
+        self.project_edition
+            .get_or_init(|| {
+                manifest_edition(manifest_dir, self.edition_strategy).map_err(|e| e.to_string())
+            })
+            .clone()
+            .map_err(Error::msg)
+    }
+
+    /// Gives `test` the attributes `chapter_attributes` has for its
+    /// chapter, unless its block already has one of them.
+    fn apply_chapter_attributes(&self, test: &mut Test) {
+        if test.compile_fail || test.should_panic || test.no_run || test.ignore {
+            return;
+        }
+        for (pattern, attributes) in &self.chapter_attributes {
+            if !pattern.matches(&test.file) {
+                continue;
+            }
+            for attribute in attributes {
+                match attribute.as_str() {
+                    "compile_fail" => test.compile_fail = true,
+                    "should_panic" => test.should_panic = true,
+                    "no_run" => test.no_run = true,
+                    "ignore" => test.ignore = true,
+                    _ => unreachable!("checked in KeeperConfig::from_parser"),
+                }
+            }
+        }
+    }
+
+    /// The target triple examples are compiled for.
+    pub fn target_triple(&self) -> &str {
+        self.target
+            .as_deref()
+            .unwrap_or(current_platform::CURRENT_PLATFORM)
+    }
+
+    /// How long this test may take to compile, if it has a budget.
+    fn compile_budget(&self, test: &Test) -> Option<Duration> {
+        test.max_compile_ms
+            .or(self.max_compile_ms)
+            .map(Duration::from_millis)
+    }
+
+    /// Returns why this test should be skipped, if keeper's
+    /// configuration means it shouldn't be run.
+    fn skip_reason(&self, test: &Test) -> Option<SkipReason> {
+        if self.skip_fragments && is_fragment(&test.text) {
+            return Some(SkipReason::Fragment);
+        }
+        let for_target = |platform: &String| platform_matches(platform, self.target_triple());
+        if test.ignore_platforms.iter().any(for_target)
+            || (!test.only_platforms.is_empty() && !test.only_platforms.iter().any(for_target))
+        {
+            return Some(SkipReason::Platform);
+        }
+        if self.previously_passed.get(&test.name) == Some(&test.code_hash()) {
+            return Some(SkipReason::PassedPreviously);
+        }
+
+        None
+    }
+
+    /// Whether this test will actually be compiled (or found in the cache).
+    fn will_run(&self, test: &Test) -> bool {
+        !self.is_ignored(test) && self.skip_reason(test).is_none()
+    }
+
+    /// Whether `result` is what `test` expected, with the options which
+    /// change that (see [`TestResult::met_test_expectations`]).
+    fn met_expectations(&self, test: &Test, result: &TestResult) -> bool {
+        match result {
+            TestResult::RunFailed(output)
+                if test.should_panic
+                    && self.should_panic_any_failure
+                    && test.expected_exit_code().is_none()
+                    && !is_sanitizer_error(output) =>
+            {
+                match &self.should_panic_pattern {
+                    Some(pattern) => panic_message_matches(output, pattern),
+                    None => true,
+                }
+            }
+            _ => result.met_test_expectations(test, self.should_panic_pattern.as_ref()),
+        }
+    }
+
+    /// The part of `test`'s hash its files are named with: the whole hash,
+    /// unless `hash_length` is set.
+    fn file_hash<'a>(&self, test: &'a Test) -> &'a str {
+        match self.hash_length {
+            Some(length) if length < test.hash.len() => &test.hash[..length],
+            _ => &test.hash,
+        }
+    }
+
+    /// The kind of crate `test` is compiled as: its own `crate_type`
+    /// directive, then `compile_fail_crate_type` (for `compile_fail`
+    /// blocks), then `default_crate_type`.
+    fn crate_type(&self, test: &Test) -> CrateType {
+        test.crate_type
+            .or(self.compile_fail_crate_type.filter(|_| test.compile_fail))
+            .unwrap_or(self.default_crate_type)
+    }
+
+    /// Whether `test` would be run, but is checked as a library instead,
+    /// because of its crate type.
+    fn compiles_as_lib(&self, test: &Test) -> bool {
+        self.crate_type(test) == CrateType::Lib && can_be_checked_instead(test)
+    }
+
+    /// Whether `test` would be run, but is only checked instead, because
+    /// it's longer than `run_size_limit_lines`.
+    fn over_run_size_limit(&self, test: &Test) -> bool {
+        match self.run_size_limit_lines {
+            Some(limit) => {
+                can_be_checked_instead(test) && test.prelude.len() + test.text.len() > limit
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `test` is ignored. Blocks ignored for one of the
+    /// `run_ignored_reasons` are run anyway.
+    fn is_ignored(&self, test: &Test) -> bool {
+        test.ignore
+            && !matches!(
+                &test.ignore_reason,
+                Some(reason) if self.run_ignored_reasons.contains(reason)
+            )
+    }
+
+    /// Makes sure examples can be compiled for `target`. Without this,
+    /// every example fails to compile with an error about `std`.
+    /// Returns whether the tests should be run.
+    fn check_target(&self) -> Result<bool, Error> {
+        let target = match &self.target {
+            Some(target) if !target_is_installed(target) => target,
+            _ => return Ok(true),
+        };
+
+        let message = format!(
+            "The target `{}` isn't installed, so examples can't be compiled for it. \
+             Install it with `rustup target add {}`.",
+            target, target
+        );
+        if self.skip_if_target_missing {
+            eprintln!(
+                "{}",
+                format!("Warning: {} Skipping all tests.", message).yellow()
+            );
+            Ok(false)
+        } else {
+            Err(Error::msg(message))
+        }
+    }
+
+    /// Makes sure `manifest_dir` (if there is one) has a `Cargo.toml`, so
+    /// a mistyped path is reported clearly, rather than by cargo.
+    fn check_manifest_dir(&self) -> Result<(), Error> {
+        if let Some(manifest_dir) = &self.manifest_dir {
+            if !manifest_dir.join("Cargo.toml").is_file() {
+                // Relative paths are the usual mistake, so show where it led.
+                return Err(Error::msg(format!(
+                    "manifest_dir '{}' does not contain a Cargo.toml (looked in {})",
+                    manifest_dir.display(),
+                    std::env::current_dir()?.join(manifest_dir).display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the project in `manifest_dir`, so examples can use it
+    /// and its dependencies. Once `cancellation` is cancelled, the build
+    /// stops, and this returns without an error.
+    fn cargo_build(
+        &self,
+        manifest_dir: &Path,
+        cancellation: &BuildCancellation,
+    ) -> Result<(), Error> {
+        self.run_cargo_build(manifest_dir, &[], cancellation)?;
+        // `--tests` builds the dev-dependencies, but not the project's
+        // libraries on their own, so it's a second build.
+        if self.include_dev_dependencies {
+            self.run_cargo_build(manifest_dir, &["--tests"], cancellation)?;
+        }
+        Ok(())
+    }
+
+    fn run_cargo_build(
+        &self,
+        manifest_dir: &Path,
+        args: &[&str],
+        cancellation: &BuildCancellation,
+    ) -> Result<(), Error> {
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+
+        let mut command = Command::new(cargo);
+        command
+            .arg("build")
+            .args(args)
+            .current_dir(manifest_dir)
+            .env("CARGO_TARGET_DIR", &self.target_dir)
+            .env("CARGO_MANIFEST_DIR", manifest_dir)
+            .stderr(Stdio::piped());
+
+        if self.is_workspace {
+            command.arg("--workspace");
+        }
+
+        if self.locked {
+            command.arg("--locked");
+        }
+
+        if self.offline {
+            command.arg("--offline");
+        }
+
+        if let Some(target) = &self.target {
+            command.args(["--target", target]);
+        }
+
+        if !self.build_features.is_empty() {
+            command.args(["--features", &self.build_features.join(",")]);
+        }
+
+        if !self.dependency_lints.is_empty() {
+            command.env("RUSTFLAGS", self.dependency_rustflags());
+        }
+
+        let stderr = {
+            let mut state = cancellation.lock();
+            if state.cancelled {
+                return Ok(());
+            }
+            let mut child = command.spawn()?;
+            let stderr = child.stderr.take();
+            state.child = Some(child);
+            stderr
+        };
+
+        // Pass cargo's output through as it happens, but keep it
+        // so we can explain what went wrong if the build fails.
+        let mut cargo_stderr = Vec::new();
+        if let Some(stderr) = stderr {
+            for line in BufReader::new(stderr).lines() {
+                let line = line?;
+                eprintln!("{}", line);
+                cargo_stderr.push(line);
+            }
+        }
+
+        let mut child = match cancellation.lock().child.take() {
+            Some(child) => child,
+            // Cancelled, so cargo has been killed, and nothing needs the build.
+            None => return Ok(()),
+        };
+        let build_status = child.wait()?;
+
+        if !build_status.success() {
+            let skipped_lines = cargo_stderr.len().saturating_sub(CARGO_ERROR_LINES);
+            let mut message = format!("`cargo build` failed in {}:\n", manifest_dir.display());
+            if skipped_lines > 0 {
+                message.push_str(&format!("[... {} lines omitted ...]\n", skipped_lines));
+            }
+            message.push_str(&cargo_stderr[skipped_lines..].join("\n"));
+            return Err(Error::msg(message));
+        }
+
+        Ok(())
+    }
+
+    /// `RUSTFLAGS` for `cargo build`, with `dependency_lints` added to
+    /// any the user already set. The lints are sorted, so the flags (and
+    /// so cargo's fingerprints) are the same from one run to the next.
+    fn dependency_rustflags(&self) -> String {
+        let mut lints = self.dependency_lints.iter().collect::<Vec<_>>();
+        lints.sort_by_key(|(lint, _)| *lint);
+
+        let mut flags = std::env::var("RUSTFLAGS").unwrap_or_default();
+        for (lint, level) in lints {
+            if !flags.is_empty() {
+                flags.push(' ');
+            }
+            flags.push_str(&format!("{} {}", level.flag(), lint));
+        }
+        flags
+    }
+
+    /// Gets the cache and project ready for tests to run. The project
+    /// must already have been built (see [`KeeperConfig::cargo_build`]).
+    fn setup_environment(&self) -> Result<(), Error> {
+        // cargo may have created `target_dir` inside it already.
+        cache::create_dir(&self.test_dir)?;
+        cache::remove_pending_files(&self.test_dir)?;
+
+        if let Some(manifest_dir) = &self.manifest_dir {
+            let edition = self.project_edition(manifest_dir)?;
+            if edition.is_none() && self.edition.is_none() {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Warning: no package in `{}` has an edition keeper knows, so \
+                         examples use {}. Set `edition` to choose another.",
+                        manifest_dir.display(),
+                        DEFAULT_EDITION
+                    )
+                    .yellow()
+                );
+            }
+
+            if !self.externs.is_empty() {
+                let names = dependency_names(manifest_dir, self.include_dev_dependencies)
+                    .map_err(|e| Error::msg(e.to_string()))?;
+                self.check_externs(&names)?;
+            }
+
+            // Examples that use these would fail to link, with an error that
+            // doesn't say why; so say why now.
+            let missing = missing_dependency_artifacts(
+                manifest_dir,
+                &self.target_dir,
+                self.target_triple(),
+                self.lockfile.as_deref(),
+                self.include_dev_dependencies,
+            )
+            .unwrap_or_default();
+            for libname in missing {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Warning: cargo built `{}`, but its library couldn't be found, so \
+                         examples can't use it. Try running `cargo clean` and rebuilding.",
+                        libname
+                    )
+                    .yellow()
+                );
+            }
+
+            if let Some(path) = &self.export_versions_path {
+                let versions = dependency_versions(
+                    manifest_dir,
+                    self.lockfile.as_deref(),
+                    self.include_dev_dependencies,
+                )
+                .map_err(|e| Error::msg(e.to_string()))?;
+                let contents =
+                    serde_json::to_string_pretty(&versions).expect("versions can be serialized");
+                std::fs::write(path, contents).map_err(|e| {
+                    Error::msg(format!(
+                        "couldn't write export_versions_path `{}`: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+            }
+        }
+
+        if let Some(deps) = &self.prebuilt_deps {
+            self.check_externs(&deps.keys().cloned().collect::<Vec<_>>())?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes the tests which use the project's dependencies depend on
+    /// the project's fingerprint too, so that changing a dependency
+    /// means they're run again, rather than reported as cached. Tests
+    /// which are run depend on the fixture's fingerprint in the same way.
+    fn fingerprint_tests(&self, tests: &mut [Test]) -> Result<(), Error> {
+        let to_error = |e: run_tests::Error| Error::msg(e.to_string());
+
+        // A test which was only checked mustn't look like it passed a run.
+        for test in tests.iter_mut() {
+            if self.compiles_as_lib(test) || self.over_run_size_limit(test) {
+                test.add_fingerprint("checked only");
+            }
+            // The code written for the test changes with `main_wrapper`.
+            if let Some(main_wrapper) = self.main_wrapper.as_ref().filter(|_| self.rustdoc_compat) {
+                test.add_fingerprint(&format!("main_wrapper={:?}", main_wrapper));
+            }
+            // It's only wrapped in `main` like rustdoc with `rustdoc_compat`.
+            if self.rustdoc_compat {
+                test.add_fingerprint("rustdoc_compat");
+            }
+            // And with the `wrapper_template`.
+            if self.wrapper_template != DEFAULT_WRAPPER_TEMPLATE {
+                test.add_fingerprint(&format!("wrapper_template={}", self.wrapper_template));
+            }
+        }
+
+        if let Some(fixture_dir) = &self.fixture_dir {
+            let fingerprint = fixture_fingerprint(fixture_dir).map_err(to_error)?;
+            for test in tests.iter_mut().filter(|test| !test.no_run) {
+                test.add_fingerprint(&fingerprint);
+            }
+        }
+
+        // What tests print can depend on these, so changing them means
+        // running the tests again.
+        if self.locale.is_some() || self.timezone.is_some() || self.seed.is_some() {
+            let fingerprint = format!(
+                "LC_ALL={:?} TZ={:?} KEEPER_SEED={:?}",
+                self.locale, self.timezone, self.seed
+            );
+            for test in tests.iter_mut().filter(|test| !test.no_run) {
+                test.add_fingerprint(&fingerprint);
+            }
+        }
+
+        let (fingerprint, mut names) = match (&self.manifest_dir, &self.prebuilt_deps) {
+            (Some(manifest_dir), _) => (
+                project_fingerprint(
+                    manifest_dir,
+                    &self.target_dir,
+                    self.target_triple(),
+                    self.lockfile.as_deref(),
+                    self.include_dev_dependencies,
+                )
+                .map_err(to_error)?,
+                dependency_names(manifest_dir, self.include_dev_dependencies).map_err(to_error)?,
+            ),
+            (None, Some(deps)) => (
+                prebuilt_fingerprint(deps).map_err(to_error)?,
+                deps.keys().cloned().collect(),
+            ),
+            (None, None) => return Ok(()),
+        };
+        names.extend(
+            self.externs
+                .iter()
+                .filter(|e| extern_glob(e).is_none())
+                .cloned(),
+        );
+
+        for test in tests {
+            if uses_dependencies(&[&test.prelude[..], &test.text[..]].concat(), &names) {
+                test.add_fingerprint(&fingerprint);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure every crate in `externs` is one of `names`, the
+    /// project's dependencies, since a typo there otherwise shows up
+    /// as a confusing error from rustc in every example.
+    fn check_externs(&self, names: &[String]) -> Result<(), Error> {
+        for extern_ in &self.externs {
+            // A glob must match at least one dependency.
+            if let Some(pattern) = extern_glob(extern_) {
+                if !names.iter().any(|name| pattern.matches(name)) {
+                    return Err(Error::msg(format!(
+                        "extern pattern '{}' doesn't match any dependency of the project",
+                        extern_
+                    )));
+                }
+                continue;
+            }
+
+            // With `alias=crate` it's the crate which must be a dependency, and
+            // with `name=path` the library is given directly, so isn't checked.
+            let name = match parse_extern_alias(extern_) {
+                Some((_, krate)) => krate.replace('-', "_"),
+                None if extern_.contains('=') => continue,
+                None => extern_.clone(),
+            };
+            if names.contains(&name) {
+                continue;
+            }
+
+            let mut message = format!("extern '{}' is not a dependency of the project", name);
+            if let Some(suggestion) = closest_name(&name, names) {
+                message.push_str(&format!("; did you mean '{}'?", suggestion));
+            }
+            return Err(Error::msg(message));
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the name in `names` which is most like `name`, if any of
+/// them are close enough to plausibly be what was meant.
+fn closest_name<'a>(name: &str, names: &'a [String]) -> Option<&'a str> {
+    let max_distance = (name.len() / 3).max(1);
+    names
+        .iter()
+        .map(|n| (edit_distance(name, n), n))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, n)| n.as_str())
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Writes the source file for `test` into `test_dir`, returning its path.
+/// This is the `testcase_path` to give [`compile_test`].
+pub fn write_test(test: &Test, config: &KeeperConfig) -> Result<PathBuf, std::io::Error> {
+    if !config.test_dir.is_dir() {
+        std::fs::create_dir_all(&config.test_dir)?;
+    }
+    let testcase_path = get_test_path(test, config);
+    write_test_to_path(test, &testcase_path, config)?;
+
+    Ok(testcase_path)
+}
+
+fn get_test_path(test: &Test, config: &KeeperConfig) -> PathBuf {
+    get_test_dir(test, config).join(format!("keeper_{}.rs", config.file_hash(test)))
+}
+
+/// Where a test is written while it's being compiled and run.
+fn get_pending_test_path(test: &Test, config: &KeeperConfig) -> PathBuf {
+    get_test_dir(test, config).join(format!(
+        "{}{}.rs",
+        cache::PENDING_PREFIX,
+        config.file_hash(test)
+    ))
+}
+
+/// The directory a test's files go in. With `shard_cache`, this is
+/// a subdirectory of `test_dir` named after the start of its hash.
+fn get_test_dir(test: &Test, config: &KeeperConfig) -> PathBuf {
+    if config.shard_cache {
+        config.test_dir.join(&test.hash[..2])
+    } else {
+        config.test_dir.clone()
+    }
+}
+
+fn write_test_to_path(
+    test: &Test,
+    path: &Path,
+    config: &KeeperConfig,
+) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut output = File::create(path)?;
+    write!(output, "{}", test_source(test, config))?;
+
+    Ok(())
+}
+
+/// The code written to a test's file.
+fn test_source(test: &Test, config: &KeeperConfig) -> String {
+    let lines = [&test.prelude[..], &test.text[..]].concat();
+    if config.rustdoc_compat {
+        create_rustdoc_test_input(
+            &lines,
+            &config.wrapper_template,
+            config.main_wrapper.as_ref(),
+        )
+    } else {
+        create_test_input(&lines, &config.wrapper_template)
+    }
+}
+
+/// Whether the cached file for `test` still holds the code keeper would
+/// write for it, for `verify_cache`. If it doesn't, a warning is printed.
+fn cached_test_is_intact(test: &Test, path: &Path, config: &KeeperConfig) -> bool {
+    let intact = matches!(
+        std::fs::read_to_string(path),
+        Ok(cached) if cached == test_source(test, config)
+    );
+    if !intact {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {} doesn't match the code of {}, so it will be run again",
+                path.display(),
+                test.name
+            )
+            .yellow()
+        );
+    }
+    intact
+}
+
+/// Groups the tests which will run that have identical code (and so the
+/// same hash), in the order they appear. Only the first of each group is
+/// compiled; the rest find it in the cache.
+fn tests_sharing_code<'a>(tests: &'a [Test], config: &KeeperConfig) -> Vec<Vec<&'a Test>> {
+    let mut by_hash: HashMap<&str, Vec<&Test>> = HashMap::new();
+    for test in tests.iter().filter(|test| config.will_run(test)) {
+        by_hash.entry(&test.hash).or_default().push(test);
+    }
+
+    let mut shared = by_hash
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect::<Vec<_>>();
+    shared.sort_by_key(|group| (&group[0].file, group[0].line));
+    shared
+}
+
+fn run_tests_with_config(
+    tests: Vec<Test>,
+    config: &KeeperConfig,
+    timings: &mut Timings,
+) -> HashMap<Test, TestResult> {
+    for group in tests_sharing_code(&tests, config) {
+        let names = group
+            .iter()
+            .map(|test| test.name.as_str())
+            .collect::<Vec<_>>();
+        eprintln!(
+            "{}",
+            format!(
+                "Note: {} examples share identical code ({}); compiling once",
+                group.len(),
+                names.join(", ")
+            )
+            .blue()
+        );
+    }
+
+    // Each run starts the file afresh; tests append their diagnostics.
+    if let Some(diagnostics_path) = &config.diagnostics_path {
+        if let Err(e) = File::create(diagnostics_path) {
+            eprintln!(
+                "{}",
+                format!(
+                    "Warning: couldn't create {}: {}",
+                    diagnostics_path.display(),
+                    e
+                )
+                .yellow()
+            );
+        }
+    }
+
+    let mut results = HashMap::new();
+    // Used to warn when a `compile_fail` block starts failing for a
+    // different reason than it used to.
+    let mut previous_error_codes = cache::read_error_codes(&config.test_dir);
+    let mut error_codes_seen = HashMap::new();
+    for test in tests {
+        if config.is_ignored(&test) {
+            continue;
+        }
+        if let Some(reason) = config.skip_reason(&test) {
+            results.insert(test, TestResult::Skipped(reason));
+            continue;
+        }
+        if let Some(dump_tests_dir) = &config.dump_tests_dir {
+            let dump_path = dump_tests_dir.join(format!("{}.rs", sanitize_test_name(&test.name)));
+            if let Err(e) = write_test_to_path(&test, &dump_path, config) {
+                eprintln!(
+                    "{}",
+                    format!("Warning: couldn't write {}: {}", dump_path.display(), e).yellow()
+                );
+            }
+        }
+
+        let testcase_path = get_test_path(&test, config);
+
+        let cached = testcase_path.is_file()
+            && (!config.verify_cache || cached_test_is_intact(&test, &testcase_path, config));
+
+        let result: TestResult = if !cached {
+            // The test is only put where the cache looks for it once it
+            // has finished; so if keeper crashes partway, it isn't later
+            // mistaken for a test which passed.
+            if !test.compile_fail {
+                if let Some(hint) = hidden_line_hint(&test.text) {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Warning: in {}:{} ({}), {}",
+                            test.file, test.line, test.name, hint
+                        )
+                        .yellow()
+                    );
+                }
+            }
+            let pending_path = get_pending_test_path(&test, config);
+            let (result, run_times) = match write_test_to_path(&test, &pending_path, config) {
+                Ok(()) => handle_test(
+                    config,
+                    &test,
+                    config.target_triple(),
+                    &pending_path,
+                    if test.no_std {
+                        // Without std there's no `main` to link against,
+                        // so all that can be done is type-checking.
+                        CompileType::Check
+                    } else if test.no_run && config.no_run_links {
+                        CompileType::Link
+                    } else if test.no_run
+                        || config.compiles_as_lib(&test)
+                        || config.over_run_size_limit(&test)
+                    {
+                        CompileType::Check
+                    } else if !config.rustdoc_compat && uses_test_harness(&test.text) {
+                        CompileType::Test
+                    } else {
+                        CompileType::Full
+                    },
+                ),
+                Err(e) => (
+                    TestResult::SpawnFailed(format!(
+                        "couldn't write {}: {}",
+                        pending_path.display(),
+                        e
+                    )),
+                    None,
+                ),
+            };
+            // Only a test which passed belongs in the cache.
+            let finished = if config.met_expectations(&test, &result) {
+                std::fs::rename(&pending_path, &testcase_path)
+            } else {
+                std::fs::remove_file(&pending_path)
+            };
+            match finished {
+                // The test's file couldn't be written in the first place.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!(
+                    "{}",
+                    format!("Warning: couldn't move {}: {}", pending_path.display(), e).yellow()
+                ),
+                Ok(()) => {}
+            }
+            if let Some(run_times) = run_times {
+                timings.runs.insert(test.name.clone(), run_times);
+            }
+            result
+        } else {
+            TestResult::Cached
+        };
+
+        if let TestResult::CompileFailed(output) = &result {
+            if test.compile_fail {
+                let codes = error_codes(output);
+                warn_if_error_codes_changed(&test, previous_error_codes.get(&test.hash), &codes);
+                error_codes_seen.insert(test.hash.clone(), codes);
+            }
+        }
+        results.insert(test, result);
+    }
+
+    record_passed_tests(config, &results);
+
+    if !error_codes_seen.is_empty() {
+        // Blocks which were edited (or removed) have a new hash, if any.
+        let hashes = results
+            .keys()
+            .map(|test| &test.hash)
+            .collect::<HashSet<_>>();
+        previous_error_codes.retain(|hash, _| hashes.contains(hash));
+        previous_error_codes.extend(error_codes_seen);
+        if let Err(e) = cache::write_error_codes(&config.test_dir, &previous_error_codes) {
+            eprintln!("Could not save compile_fail error codes: {}", e);
+        }
+    }
+
+    results
+}
+
+/// Records which tests passed, so `only_failed` can skip them next time.
+/// Tests which weren't run keep what was recorded for them before.
+fn record_passed_tests(config: &KeeperConfig, results: &HashMap<Test, TestResult>) {
+    if results
+        .values()
+        .all(|r| matches!(r, TestResult::Skipped(_)))
+    {
+        return;
+    }
 
-    /// This allows you to specify if the manifest dir is
-    /// of a cargo workspace. If set to true, `--workspace`
-    /// will be passed to the invocation of `cargo build`.
-    #[serde(default)]
-    is_workspace: Option<bool>,
+    let mut passed = cache::read_passed(&config.test_dir);
+    passed.retain(|name, _| results.keys().any(|test| &test.name == name));
+    for (test, result) in results {
+        match result {
+            TestResult::Skipped(_) => {}
+            _ if config.met_expectations(test, result) => {
+                passed.insert(test.name.clone(), test.code_hash());
+            }
+            _ => {
+                passed.remove(&test.name);
+            }
+        }
+    }
 
-    /// This allows you to specify the features you want to
-    /// invoke `cargo build` with. If you set this to
-    /// `["first", "second"], it  causes `--features
-    /// first,second` to be added to the invocation of
-    /// `cargo build`.
-    #[serde(default)]
-    build_features: Vec<String>,
+    if let Err(e) = cache::write_passed(&config.test_dir, &passed) {
+        eprintln!("Could not save which tests passed: {}", e);
+    }
+}
 
-    /// Whether to show terminal colours.
-    #[serde(default)]
-    terminal_colors: Option<bool>,
+/// The tests whose results differ from the last run, by name.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct StatusChanges {
+    newly_failing: Vec<String>,
+    newly_passing: Vec<String>,
+    added: Vec<String>,
+    removed: Vec<String>,
 }
 
-#[derive(Debug)]
-struct KeeperConfig {
-    test_dir: PathBuf,
-    target_dir: PathBuf,
-    manifest_dir: Option<PathBuf>,
-    is_workspace: bool,
-    build_features: Vec<String>,
-    terminal_colors: bool,
-    externs: Vec<String>,
+impl StatusChanges {
+    fn is_empty(&self) -> bool {
+        self.newly_failing.is_empty()
+            && self.newly_passing.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+    }
 }
 
-impl KeeperConfig {
-    fn new(preprocessor_config: PreprocessorConfig, root: &Path) -> KeeperConfig {
-        let keeper_config: KeeperConfigParser = match preprocessor_config {
-            Some(config) => toml::de::from_str(
-                &toml::ser::to_string(&config).expect("this must succeed, it was just toml"),
-            )
-            .unwrap(),
-            None => KeeperConfigParser::default(),
+/// Records whether each test passed, and compares that with what was
+/// recorded the last time. On the first run nothing has changed, rather
+/// than every test being new. Tests which weren't run keep what was
+/// recorded for them before.
+fn record_statuses(config: &KeeperConfig, results: &HashMap<Test, TestResult>) -> StatusChanges {
+    let mut changes = StatusChanges::default();
+    if results
+        .values()
+        .all(|r| matches!(r, TestResult::Skipped(_)))
+    {
+        return changes;
+    }
+
+    let previous = cache::read_statuses(&config.test_dir);
+    let mut statuses = HashMap::new();
+    for (test, result) in sorted_results(results) {
+        let was_passing = previous
+            .as_ref()
+            .and_then(|previous| previous.get(&test.name))
+            .copied();
+        let passing = match result {
+            TestResult::Skipped(_) => match was_passing {
+                Some(was_passing) => was_passing,
+                None => continue,
+            },
+            _ => config.met_expectations(test, result),
         };
+        statuses.insert(test.name.clone(), passing);
 
-        let base_dir = root.to_path_buf();
-        let test_dir = keeper_config
-            .test_dir
-            .map(PathBuf::from)
-            .unwrap_or_else(|| {
-                let mut build_dir = base_dir;
-                build_dir.push("doctest_cache");
-                build_dir
-            });
+        match (was_passing, passing) {
+            (Some(true), false) => changes.newly_failing.push(test.name.clone()),
+            (Some(false), true) => changes.newly_passing.push(test.name.clone()),
+            (None, _) if previous.is_some() => changes.added.push(test.name.clone()),
+            _ => {}
+        }
+    }
 
-        let target_dir = keeper_config
-            .target_dir
-            .map(PathBuf::from)
-            .unwrap_or_else(|| {
-                let mut target_dir = test_dir.clone();
-                target_dir.push("target");
-                target_dir
-            });
+    if let Some(previous) = previous {
+        changes.removed = previous
+            .into_keys()
+            .filter(|name| !results.keys().any(|test| &test.name == name))
+            .collect();
+        changes.removed.sort();
+    }
 
-        let manifest_dir = keeper_config.manifest_dir.map(PathBuf::from);
-        let is_workspace = keeper_config.is_workspace.unwrap_or(false);
+    if let Err(e) = cache::write_statuses(&config.test_dir, &statuses) {
+        eprintln!("Could not save the results of the tests: {}", e);
+    }
 
-        let terminal_colors = keeper_config
-            .terminal_colors
-            .unwrap_or_else(|| atty::is(Stream::Stderr));
+    changes
+}
 
-        set_override(terminal_colors);
+/// Prints a line counting the tests whose results changed since the last
+/// run, and then their names.
+fn print_status_changes(changes: &StatusChanges) {
+    if changes.is_empty() {
+        return;
+    }
 
-        KeeperConfig {
-            test_dir,
-            target_dir,
-            manifest_dir,
-            is_workspace,
-            build_features: keeper_config.build_features,
-            terminal_colors,
-            externs: keeper_config.externs,
+    let categories = [
+        ("newly failing", &changes.newly_failing),
+        ("newly passing", &changes.newly_passing),
+        ("new", &changes.added),
+        ("removed", &changes.removed),
+    ];
+    let counts = categories
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(category, names)| format!("{} {}", names.len(), category))
+        .collect::<Vec<_>>();
+    eprintln!(
+        "{} {}",
+        "Since the last run:".bold(),
+        counts.join(", ").bold()
+    );
+    for (category, names) in categories {
+        if !names.is_empty() {
+            eprintln!(" - {}: {}", category, names.join(", "));
+        }
+    }
+}
+
+/// Whether a code block would be tested, and if not, why not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Coverage {
+    Tested,
+    /// The block is marked `ignore`, with the reason it gave (if any).
+    Ignored(Option<String>),
+    /// The block looks like a fragment of a larger program, and
+    /// `skip_fragments` is set.
+    Fragment,
+    /// keeper's configuration means the block isn't tested, like
+    /// `only_failed` or a platform tag.
+    SkippedByConfig(SkipReason),
+}
+
+impl std::fmt::Display for Coverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Coverage::Tested => write!(f, "tested"),
+            Coverage::Ignored(Some(reason)) => write!(f, "ignored ({})", reason),
+            Coverage::Ignored(None) => write!(f, "ignored"),
+            Coverage::Fragment => write!(f, "fragment"),
+            Coverage::SkippedByConfig(reason) => write!(f, "skipped ({})", reason),
         }
     }
+}
+
+/// Works out which of `tests` would be tested, for `audit`, sorted by
+/// file and line. Like a real run, there's a test for each edition
+/// in `test_editions`.
+fn audit_tests(tests: Vec<Test>, config: &KeeperConfig) -> Vec<(Test, Coverage)> {
+    let mut tests = expand_tests(tests, config);
+    tests.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    tests
+        .into_iter()
+        .map(|test| {
+            let coverage = if config.is_ignored(&test) {
+                Coverage::Ignored(test.ignore_reason.clone())
+            } else {
+                match config.skip_reason(&test) {
+                    Some(SkipReason::Fragment) => Coverage::Fragment,
+                    Some(reason) => Coverage::SkippedByConfig(reason),
+                    None => Coverage::Tested,
+                }
+            };
+            (test, coverage)
+        })
+        .collect()
+}
+
+/// Prints which code blocks would be tested, grouped by chapter.
+fn print_audit(audit: &[(Test, Coverage)]) {
+    let tested = audit
+        .iter()
+        .filter(|(_, coverage)| *coverage == Coverage::Tested)
+        .count();
+    eprintln!(
+        "{}",
+        format!(
+            "Audit: {} of {} code blocks are tested",
+            tested,
+            audit.len()
+        )
+        .bold()
+    );
 
-    fn setup_environment(&self) {
-        if !self.test_dir.is_dir() {
-            std::fs::create_dir(&self.test_dir).unwrap();
+    let mut file = None;
+    for (test, coverage) in audit {
+        if file != Some(&test.file) {
+            file = Some(&test.file);
+            eprintln!(" {}", test.file.bold());
         }
+        let description = match coverage {
+            Coverage::Tested => coverage.to_string().green(),
+            _ => coverage.to_string().yellow(),
+        };
+        eprintln!("   line {:<6} {}", test.line, description);
+    }
+}
 
-        if let Some(manifest_dir) = &self.manifest_dir {
-            let cargo = std::env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+/// Warns if a `compile_fail` block fails with different error codes than
+/// it did last time, as it may no longer be demonstrating the same error.
+fn warn_if_error_codes_changed(test: &Test, previous: Option<&Vec<String>>, codes: &[String]) {
+    match previous {
+        Some(previous) if previous != codes => eprintln!(
+            "{}",
+            format!(
+                "Warning: compile_fail block's error changed in {} (was [{}], now [{}])",
+                test.name,
+                previous.join(", "),
+                codes.join(", ")
+            )
+            .yellow()
+        ),
+        _ => {}
+    }
+}
+
+/// Sorts results into the order they appear in the book (by chapter,
+/// then by line), so the report is the same every time.
+fn sorted_results(results: &HashMap<Test, TestResult>) -> Vec<(&Test, &TestResult)> {
+    let mut sorted = results.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(test, _)| (test.file_position, &test.file, test.line, &test.name));
+    sorted
+}
 
-            let mut command = Command::new(cargo);
-            command
-                .arg("build")
-                .current_dir(manifest_dir)
-                .env("CARGO_TARGET_DIR", &self.target_dir)
-                .env("CARGO_MANIFEST_DIR", manifest_dir);
+/// The most lines of expected and actual output (multiplied together)
+/// [`output_diff`] matches up, as it needs memory for each pair. Past
+/// this, lines are just compared by position.
+const MAX_DIFF_PAIRS: usize = 1_000_000;
 
-            if self.is_workspace {
-                command.arg("--workspace");
-            }
+/// Compares the expected and actual output of a test line by line,
+/// marking expected lines which weren't printed with `-`, and printed
+/// lines which weren't expected with `+`. Lines are matched up with a
+/// longest common subsequence, so one missing or extra line doesn't
+/// make every line after it differ.
+fn output_diff(expected: &str, actual: &str) -> String {
+    let expected = normalize_output(expected);
+    let actual = normalize_output(actual);
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
 
-            if !self.build_features.is_empty() {
-                command.args(["--features", &self.build_features.join(",")]);
-            }
+    // Lines the two start and end with don't need matching up.
+    let prefix = expected
+        .iter()
+        .zip(&actual)
+        .take_while(|(e, a)| e == a)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(e, a)| e == a)
+        .count();
+    let changed_expected = &expected[prefix..expected.len() - suffix];
+    let changed_actual = &actual[prefix..actual.len() - suffix];
 
-            let mut join_handle = command.spawn().expect("failed to execute process");
+    let mut diff = String::new();
+    for line in &expected[..prefix] {
+        diff.push_str(&format!("  {}\n", line));
+    }
+    if changed_expected.len() * changed_actual.len() <= MAX_DIFF_PAIRS {
+        matched_diff(changed_expected, changed_actual, &mut diff);
+    } else {
+        positional_diff(changed_expected, changed_actual, &mut diff);
+    }
+    for line in &expected[expected.len() - suffix..] {
+        diff.push_str(&format!("  {}\n", line));
+    }
+    diff
+}
 
-            let build_was_ok = join_handle.wait().expect("Could not join on thread");
+/// The part of [`output_diff`] which matches lines up.
+fn matched_diff(expected: &[&str], actual: &[&str], diff: &mut String) {
+    // `common[i][j]` is how many lines `expected[i..]` and `actual[j..]`
+    // have in common.
+    let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
 
-            if !build_was_ok.success() {
-                panic!("cargo build failed!");
-            }
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            diff.push_str(&format!("  {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            diff.push_str(&format!("{}\n", format!("- {}", expected[i]).red()));
+            i += 1;
+        } else {
+            diff.push_str(&format!("{}\n", format!("+ {}", actual[j]).green()));
+            j += 1;
         }
     }
 }
 
-fn get_test_path(test: &Test, test_dir: &Path) -> PathBuf {
-    let mut file_name: PathBuf = test_dir.to_path_buf();
-    file_name.push(format!("keeper_{}.rs", test.hash));
-
-    file_name
+/// Like [`matched_diff`], but only comparing the lines at each position,
+/// for outputs too long to match up.
+fn positional_diff(expected: &[&str], actual: &[&str], diff: &mut String) {
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => diff.push_str(&format!("  {}\n", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    diff.push_str(&format!("{}\n", format!("- {}", e).red()));
+                }
+                if let Some(a) = a {
+                    diff.push_str(&format!("{}\n", format!("+ {}", a).green()));
+                }
+            }
+        }
+    }
 }
 
-fn write_test_to_path(test: &Test, path: &Path) -> Result<(), std::io::Error> {
-    let mut output = File::create(path)?;
-    let test_text = create_test_input(&test.text);
-    write!(output, "{}", test_text)?;
+/// How long the slow parts of a run took, printed after the results.
+#[derive(Debug, Default)]
+struct Timings {
+    /// Building the project in `manifest_dir`, if keeper built it.
+    build: Option<Duration>,
+    /// Compiling and running the tests. Cached tests add next to nothing.
+    tests: Duration,
+    /// How long each test took to run, by name, for tests which were run
+    /// `run_iterations` times.
+    runs: HashMap<String, RunTimes>,
+}
 
-    Ok(())
+/// Counts of each kind of result, printed for `machine_summary`.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    /// Tests keeper skipped (for instance, because they're fragments).
+    ignored: usize,
+    cached: usize,
 }
 
-fn run_tests_with_config(tests: Vec<Test>, config: &KeeperConfig) -> HashMap<Test, TestResult> {
-    let mut results = HashMap::new();
-    for test in tests {
-        if test.ignore {
-            continue;
+fn summarize(results: &HashMap<Test, TestResult>, config: &KeeperConfig) -> Summary {
+    let mut summary = Summary::default();
+    for (test, result) in results {
+        match result {
+            TestResult::Cached => summary.cached += 1,
+            TestResult::Skipped(_) => summary.ignored += 1,
+            _ if config.met_expectations(test, result) => summary.passed += 1,
+            _ => summary.failed += 1,
         }
-        let testcase_path = get_test_path(&test, &config.test_dir);
-
-        let result: TestResult = if !testcase_path.is_file() {
-            write_test_to_path(&test, &testcase_path).unwrap();
-            handle_test(
-                config.manifest_dir.as_deref(),
-                &config.target_dir,
-                current_platform::CURRENT_PLATFORM,
-                &testcase_path,
-                if test.no_run {
-                    CompileType::Check
-                } else {
-                    CompileType::Full
-                },
-                config.terminal_colors,
-                &config.externs,
-            )
-        } else {
-            TestResult::Cached
-        };
-        results.insert(test, result);
     }
-
-    results
+    summary
 }
 
-fn print_results(results: &HashMap<Test, TestResult>) {
+/// Writes a report of `results` to `out` (stderr, when run by mdbook).
+fn print_results(
+    out: &mut impl Write,
+    results: &HashMap<Test, TestResult>,
+    config: &KeeperConfig,
+    timings: &Timings,
+) -> io::Result<()> {
     let mut cached_tests = 0;
-    for (test, test_result) in results {
+    let mut passed_previously = 0;
+    for (test, test_result) in sorted_results(results) {
+        if let TestResult::Skipped(SkipReason::PassedPreviously) = test_result {
+            passed_previously += 1;
+            continue;
+        }
         if !matches!(test_result, &TestResult::Cached) {
-            eprint!(" - Test: {} ", test.name);
+            write!(out, " - Test: {} ", test.name)?;
+        }
+        if config.verbose {
+            let test_path = get_test_path(test, config);
+            match test_result {
+                TestResult::Cached => writeln!(
+                    out,
+                    " - Test: {} {}",
+                    test.name,
+                    format!("(cached: {})", test_path.display()).blue()
+                )?,
+                TestResult::Skipped(_) => {}
+                _ => write!(
+                    out,
+                    "{} ",
+                    format!("(built: {})", test_path.display()).blue()
+                )?,
+            }
         }
         let output = match test_result {
+            TestResult::Successful(output) | TestResult::RunFailed(output)
+                if test.expected_exit_code().is_some() =>
+            {
+                let expected = test.expected_exit_code().unwrap();
+                match output.status.code() {
+                    Some(code) if code == expected => writeln!(
+                        out,
+                        "{}",
+                        format!("(Exited with code {} as expected)", code).green()
+                    )?,
+                    Some(code) => writeln!(
+                        out,
+                        "{}",
+                        format!("(Exited with code {}, expected {})", code, expected).red()
+                    )?,
+                    None => writeln!(
+                        out,
+                        "{}",
+                        format!("(Killed by a signal, expected exit code {})", expected).red()
+                    )?,
+                }
+                output
+            }
             TestResult::CompileFailed(output) if test.compile_fail => {
-                eprintln!("{}", "(Failed to compile as expected)".green());
+                writeln!(out, "{}", "(Failed to compile as expected)".green())?;
                 output
             }
             TestResult::CompileFailed(output) => {
-                eprintln!("{}", "(Failed to compile)".red());
+                writeln!(out, "{}", "(Failed to compile)".red())?;
+                output
+            }
+            TestResult::RunFailed(output) if is_sanitizer_error(output) => {
+                writeln!(out, "{}", "(Sanitizer detected an error)".red())?;
+                output
+            }
+            TestResult::RunFailed(output)
+                if test.should_panic && !config.should_panic_any_failure && !is_panic(output) =>
+            {
+                writeln!(
+                    out,
+                    "{}",
+                    format!("({}, rather than panicking)", describe_exit(output)).red()
+                )?;
+                output
+            }
+            TestResult::RunFailed(output)
+                if test.should_panic && !config.met_expectations(test, test_result) =>
+            {
+                writeln!(
+                    out,
+                    "{}",
+                    format!(
+                        "(Panicked, but not with a message matching `{}`)",
+                        config.should_panic_pattern.as_ref().unwrap()
+                    )
+                    .red()
+                )?;
                 output
             }
             TestResult::RunFailed(output) if test.should_panic => {
-                eprintln!("{}", "(Panicked as expected)".green());
+                writeln!(out, "{}", "(Panicked as expected)".green())?;
                 output
             }
             TestResult::RunFailed(output) => {
-                eprintln!("{}", "(Panicked)".red());
+                writeln!(out, "{}", format!("({})", describe_exit(output)).red())?;
+                output
+            }
+            TestResult::CompileTooSlow(output, compile_time) => {
+                let budget = config.compile_budget(test).unwrap_or_default();
+                writeln!(
+                    out,
+                    "{}",
+                    format!(
+                        "(Took {}ms to compile, over its budget of {}ms)",
+                        compile_time.as_millis(),
+                        budget.as_millis()
+                    )
+                    .red()
+                )?;
+                output
+            }
+            TestResult::WrongOutput(output) => {
+                writeln!(out, "{}", "(Printed the wrong output)".red())?;
+                writeln!(
+                    out,
+                    "Output of {} ({}, line {}), with {} expected and {} printed:",
+                    test.name,
+                    test.file,
+                    test.line,
+                    "-".red(),
+                    "+".green()
+                )?;
+                let expected = test.expected_output.as_deref().unwrap_or_default();
+                let actual = test_result.stdout().unwrap_or_default();
+                write!(out, "{}", output_diff(expected, &actual))?;
                 output
             }
             TestResult::Successful(output) if test.should_panic => {
-                eprintln!("{}", "(Unexpectedly suceeded)".red());
+                writeln!(out, "{}", "(Unexpectedly suceeded)".red())?;
+                output
+            }
+            TestResult::Successful(output) if config.over_run_size_limit(test) => {
+                writeln!(
+                    out,
+                    "{}",
+                    format!(
+                        "(Checked, but not run, since it's over {} lines)",
+                        config.run_size_limit_lines.unwrap_or_default()
+                    )
+                    .green()
+                )?;
                 output
             }
             TestResult::Successful(output) => {
-                eprintln!("{}", "(Passed)".green());
+                match output.status.code() {
+                    // Only possible with `allowed_exit_codes`.
+                    Some(code) if code != 0 => writeln!(
+                        out,
+                        "{}",
+                        format!("(Passed, exiting with allowed code {})", code).green()
+                    )?,
+                    _ => writeln!(out, "{}", "(Passed)".green())?,
+                }
                 output
             }
+            TestResult::SpawnFailed(error) => {
+                writeln!(
+                    out,
+                    "{}",
+                    format!("(Could not execute compiled binary: {})", error).red()
+                )?;
+                continue;
+            }
             TestResult::Cached => {
                 cached_tests += 1;
                 continue;
             }
+            TestResult::Skipped(reason) => {
+                writeln!(out, "{}", format!("(skipped: {})", reason).yellow())?;
+                continue;
+            }
         };
-        if !test_result.met_test_expectations(test) {
-            eprintln!(
+        if let Some(run_times) = timings.runs.get(&test.name) {
+            writeln!(
+                out,
+                "   Ran {} times: min {:.1}ms, median {:.1}ms, max {:.1}ms",
+                run_times.iterations,
+                run_times.min.as_secs_f64() * 1000.0,
+                run_times.median.as_secs_f64() * 1000.0,
+                run_times.max.as_secs_f64() * 1000.0
+            )?;
+        }
+        if !config.met_expectations(test, test_result) {
+            writeln!(
+                out,
                 "--------------- {} {} ---------------",
                 "Start of Test Log: ".bold(),
                 test.name
-            );
-            if !output.stdout.is_empty() {
-                eprintln!(
+            )?;
+            if matches!(test_result, TestResult::WrongOutput(_)) {
+                // The diff above already shows what was printed.
+            } else if !output.stdout.is_empty() {
+                writeln!(
+                    out,
                     "----- {} -----\n{}",
                     "Stdout".bold(),
-                    String::from_utf8(output.stdout.to_vec()).unwrap()
-                );
-            } else {
-                eprintln!("{}", "No stdout was captured.".red(),);
+                    chapter_locations(&test_result.stdout().unwrap_or_default(), test, config)
+                )?;
+            } else if config.show_empty_streams {
+                writeln!(out, "{}", "No stdout was captured.".red(),)?;
             }
             if !output.stderr.is_empty() {
-                eprintln!(
+                writeln!(
+                    out,
                     "----- {} -----\n\n{}",
                     "Stderr".bold(),
-                    String::from_utf8(output.stderr.to_vec()).unwrap()
-                );
-            } else {
-                eprintln!("{}", "No stderr was captured.".red(),);
+                    chapter_locations(&test_result.stderr().unwrap_or_default(), test, config)
+                )?;
+            } else if config.show_empty_streams {
+                writeln!(out, "{}", "No stderr was captured.".red(),)?;
             }
-            eprintln!("--------------- End Of Test ---------------");
+            writeln!(out, "--------------- End Of Test ---------------")?;
         }
     }
 
     if cached_tests > 0 {
-        eprintln!(
+        writeln!(
+            out,
             "{} {} {}",
             "Skipped".bold(),
             cached_tests.to_string().bold().blue(),
             "tests which had identical code, and previously passed.".bold()
-        );
+        )?;
+    }
+
+    if passed_previously > 0 {
+        writeln!(
+            out,
+            "{} {} {}",
+            "Skipped".bold(),
+            passed_previously.to_string().bold().blue(),
+            "tests which passed last time, because `only_failed` is set.".bold()
+        )?;
+    }
+
+    if let Some(build) = timings.build {
+        writeln!(
+            out,
+            "{} {}",
+            "Built manifest_dir in".bold(),
+            format!("{:.1}s", build.as_secs_f64()).bold()
+        )?;
     }
+    let ran = results
+        .values()
+        .filter(|result| !matches!(result, TestResult::Cached | TestResult::Skipped(_)))
+        .count();
+    if ran > 0 {
+        writeln!(
+            out,
+            "{} {} {} {}",
+            "Ran".bold(),
+            ran.to_string().bold().blue(),
+            "tests in".bold(),
+            format!("{:.1}s", timings.tests.as_secs_f64()).bold()
+        )?;
+    }
+
+    if config.machine_summary {
+        let summary = summarize(results, config);
+        writeln!(
+            out,
+            "KEEPER_SUMMARY {}",
+            serde_json::to_string(&summary).expect("summary can be serialized")
+        )?;
+    }
+
+    Ok(())
+}
+
+/// `text` (from rustc, or the test itself), with each location in the
+/// file written for `test`, like `pending_<hash>.rs:12:5`, replaced with
+/// where that line is in the book, like `chapter_3.md:57:5`. Locations
+/// of lines keeper added are left as they are.
+fn chapter_locations(text: &str, test: &Test, config: &KeeperConfig) -> String {
+    let location = Regex::new(&format!(
+        r"\S*?(?:{}|keeper_){}\.rs:(\d+)",
+        regex::escape(cache::PENDING_PREFIX),
+        regex::escape(config.file_hash(test))
+    ))
+    .expect("location pattern is valid");
+    let lines = chapter_lines(test, config);
+
+    location
+        .replace_all(text, |caps: &regex::Captures| {
+            let chapter_line = caps[1]
+                .parse::<usize>()
+                .ok()
+                .and_then(|line| *lines.get(line.checked_sub(1)?)?);
+            match chapter_line {
+                Some(line) => format!("{}:{}", test.file, line),
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
 }
 
-fn clean_file(test_results: &HashMap<Test, TestResult>, path: &Path) -> Option<()> {
+/// Removes the file at `path` if it's for a test which failed (or moved).
+/// Files which aren't for any of `test_results` are removed only if
+/// `remove_unknown` is set. Returns whether the file was unknown.
+fn clean_file(
+    config: &KeeperConfig,
+    test_results: &HashMap<Test, TestResult>,
+    path: &Path,
+    remove_unknown: bool,
+) -> Option<bool> {
     // If the file doesn't contain a hash in the right format, we quit.
     let file_stem = path.file_stem()?;
     let file_str = file_stem.to_str()?;
     let hash = file_str.strip_prefix("keeper_")?;
 
-    let matching_test = test_results.iter().find(|(t, _)| t.hash == hash);
+    let matching_test = test_results
+        .iter()
+        .find(|(t, _)| config.file_hash(t) == hash);
 
+    // Files left where the test no longer goes (because `shard_cache`
+    // was changed) are removed too.
     let should_remove = match matching_test {
-        Some((t, tr)) => !tr.met_test_expectations(t),
-        None => true,
+        Some((t, tr)) => path != get_test_path(t, config) || !config.met_expectations(t, tr),
+        None => remove_unknown,
     };
 
     if should_remove {
         std::fs::remove_file(path).expect("Should be able to delete cache-file");
     }
 
-    Some(())
+    Some(matching_test.is_none())
 }
 
 fn cleanup_keepercache(config: &KeeperConfig, test_results: &HashMap<Test, TestResult>) {
+    // A temporary `test_dir` is removed as a whole.
+    if config.temporary_test_dir.is_some() {
+        return;
+    }
+
+    // A `test_dir` shared with another book has that book's tests in
+    // it, which mustn't be removed just because this book lacks them;
+    // but this book's own failing tests still are.
+    let owner = cache::read_owner(&config.test_dir);
+    if let Some(owner) = owner.as_ref().filter(|owner| **owner != config.book_name) {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {} is the cache of the book \"{}\", so old tests in it won't be \
+                 removed. Give this book its own `test_dir`.",
+                config.test_dir.display(),
+                owner
+            )
+            .yellow()
+        );
+        for path in cache::cache_files(&config.test_dir, "keeper_*.rs") {
+            clean_file(config, test_results, &path, false);
+        }
+        return;
+    }
+
     // Go through every file that's like keeper_*.rs
     // If the test passed, keep the file otherwise, delete it.
-    let glob_str = format!("{}/keeper_*.rs", config.test_dir.display());
-    glob(&glob_str)
-        .expect("Could not list keeper files.")
-        .filter_map(Result::ok)
-        .for_each(|p| {
-            clean_file(test_results, &p);
-        });
+    // Until the cache is known to be this book's, other files are left.
+    let unknown = cache::cache_files(&config.test_dir, "keeper_*.rs")
+        .filter(|p| clean_file(config, test_results, p, owner.is_some()) == Some(true))
+        .count();
+
+    if owner.is_none() {
+        if unknown > 0 {
+            eprintln!(
+                "{}",
+                format!(
+                    "Warning: {} has {} cached tests which aren't from this book; they'll \
+                     be removed next time, unless `test_dir` is changed.",
+                    config.test_dir.display(),
+                    unknown
+                )
+                .yellow()
+            );
+        }
+        if let Err(e) = cache::write_owner(&config.test_dir, &config.book_name) {
+            eprintln!("Could not record which book owns the cache: {}", e);
+        }
+    }
+}
+
+/// Whether `platform` (from an `ignore-<platform>` or `<platform>-only`
+/// tag) describes the target `triple`. Like rustdoc's `ignore-<target>`,
+/// any part of the triple matches, so `windows`, `linux` and `x86_64` all
+/// work; `unix` matches any target which isn't Windows or WebAssembly.
+fn platform_matches(platform: &str, triple: &str) -> bool {
+    if platform == "unix" {
+        return !triple.contains("windows") && !triple.contains("wasm");
+    }
+    triple.contains(platform)
+}
+
+/// Removes what keeper cached in `test_dir`, for `clear_cache`, unless
+/// it's the cache of another book.
+fn clear_cache(test_dir: &Path, book_name: &str) -> Result<(), Error> {
+    if let Some(owner) = cache::read_owner(test_dir).filter(|owner| owner != book_name) {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: {} is the cache of the book \"{}\", so it wasn't cleared. Give this \
+                 book its own `test_dir`.",
+                test_dir.display(),
+                owner
+            )
+            .yellow()
+        );
+        return Ok(());
+    }
+
+    let removed = cache::clear(test_dir)?;
+    eprintln!(
+        "{}",
+        format!(
+            "Cleared {} files from {}. Remove `clear_cache` to use the cache again.",
+            removed,
+            test_dir.display()
+        )
+        .blue()
+    );
+    Ok(())
+}
+
+/// What the book in `root` is called (its title, or else the name of
+/// its directory), to tell its cache apart from other books'.
+fn book_name(root: &Path) -> String {
+    let title = std::fs::read_to_string(root.join("book.toml"))
+        .ok()
+        .and_then(|contents| contents.parse::<toml::Value>().ok())
+        .and_then(|book_toml| Some(book_toml.get("book")?.get("title")?.as_str()?.to_string()));
+    title.unwrap_or_else(|| {
+        root.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    })
+}
+
+/// Decides between the edition a block gives and the configured one,
+/// when they differ: the block's wins, unless `edition_override` is set.
+/// With `verbose`, each disagreement is noted.
+fn resolve_block_editions(tests: &mut [Test], config: &KeeperConfig) {
+    if !(config.edition_override || config.verbose) || tests.iter().all(|t| t.edition.is_none()) {
+        return;
+    }
+
+    let configured = config.edition.clone().or_else(|| {
+        let manifest_dir = config.manifest_dir.as_ref()?;
+        // A bad manifest is reported later, if any test needs it.
+        let edition = config.project_edition(manifest_dir).ok()?;
+        Some(edition.unwrap_or(DEFAULT_EDITION).to_string())
+    });
+
+    for test in tests.iter_mut() {
+        let block = match &test.edition {
+            Some(block) if Some(block) != configured.as_ref() => block.clone(),
+            _ => continue,
+        };
+        let configured = configured.as_deref().unwrap_or("rustc's default");
+        if config.verbose {
+            eprintln!(
+                "{}",
+                format!(
+                    "Note: {} gives edition {}, but the configured edition is {}; using {}",
+                    test.name,
+                    block,
+                    configured,
+                    if config.edition_override {
+                        configured
+                    } else {
+                        &block
+                    }
+                )
+                .blue()
+            );
+        }
+        if config.edition_override {
+            test.edition = None;
+            // The block's tag alone doesn't change its hash, so without
+            // this it'd stay cached after being tested with its own edition.
+            test.add_fingerprint("edition_override");
+        }
+    }
+}
+
+/// Replaces each test which should be compiled under several editions
+/// (from `test_editions`, or an `//@ editions:` directive) with a copy
+/// for each edition, named like `<name>@2021`. Tests which give a single
+/// edition of their own are left as they are.
+fn expand_editions(tests: Vec<Test>, config: &KeeperConfig) -> Vec<Test> {
+    let mut expanded = Vec::with_capacity(tests.len());
+    for test in tests {
+        let editions = if !test.editions.is_empty() {
+            test.editions.clone()
+        } else if test.edition.is_none() {
+            config.test_editions.clone()
+        } else {
+            Vec::new()
+        };
+        if editions.is_empty() {
+            expanded.push(test);
+            continue;
+        }
+
+        for edition in editions {
+            let mut copy = test.clone();
+            copy.name = format!("{}@{}", test.name, edition);
+            copy.add_fingerprint(&format!("edition{}", edition));
+            copy.edition = Some(edition);
+            expanded.push(copy);
+        }
+    }
+    expanded
+}
+
+/// Turns the tests found in the book into the tests which are run: one
+/// for each edition in `test_editions`, and with `no_std` and
+/// `check_only` applied.
+fn expand_tests(mut tests: Vec<Test>, config: &KeeperConfig) -> Vec<Test> {
+    resolve_block_editions(&mut tests, config);
+    let mut tests = expand_editions(tests, config);
+    if config.no_std {
+        for test in &mut tests {
+            test.no_std = true;
+            test.no_run = true;
+        }
+    }
+    if config.check_only {
+        for test in tests
+            .iter_mut()
+            .filter(|test| !test.should_panic && !test.no_run)
+        {
+            test.no_run = true;
+            // Otherwise, the test would stay cached once checking
+            // is turned off, without ever having been run.
+            test.add_fingerprint("check_only");
+        }
+    }
+    tests
+}
+
+/// Updates the book now that its tests have run: adding their results
+/// (with `annotate_blocks`), and removing the blocks readers shouldn't see.
+fn finish_book(book: &mut Book, results: &HashMap<Test, TestResult>, config: &KeeperConfig) {
+    if config.annotate_blocks {
+        annotate_results(book, results, config);
+    }
+    hide_blocks(book);
+}
+
+/// Adds a note of each test's result after its code block. A block
+/// tested in several editions gets a note for each of them.
+fn annotate_results(book: &mut Book, results: &HashMap<Test, TestResult>, config: &KeeperConfig) {
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut ch) = *item {
+            let file = chapter_file_name(ch);
+            ch.content = annotate_blocks(&ch.content, |line| {
+                let mut block_results = results
+                    .iter()
+                    .filter(|(test, _)| test.file == file && test.line == line)
+                    .collect::<Vec<_>>();
+                if block_results.is_empty() {
+                    return None;
+                }
+                block_results.sort_by(|(a, _), (b, _)| a.edition.cmp(&b.edition));
+                let name_editions = block_results.len() > 1;
+                let notes = block_results
+                    .into_iter()
+                    .map(|(test, result)| result_note(test, result, config, name_editions))
+                    .collect::<Vec<_>>();
+                Some(notes.join("\n"))
+            });
+        }
+    });
+}
+
+/// A short note of how `test` did, as HTML that can be styled through
+/// the `keeper-result` class (and `keeper-passed`, `keeper-failed`...).
+/// With `name_edition`, the note says which edition it's about.
+fn result_note(
+    test: &Test,
+    result: &TestResult,
+    config: &KeeperConfig,
+    name_edition: bool,
+) -> String {
+    let (kind, mut description) = match result {
+        TestResult::Cached => ("cached", String::from("passed (cached)")),
+        TestResult::Skipped(reason) => ("skipped", format!("skipped ({})", reason)),
+        _ if config.met_expectations(test, result) => ("passed", String::from("passed")),
+        _ => ("failed", String::from("failed")),
+    };
+    if let (true, Some(edition)) = (name_edition, &test.edition) {
+        description = format!("{} in edition {}", description, edition);
+    }
+    format!(
+        "<p class=\"keeper-result keeper-{}\"><small>Tested with mdbook-keeper: {}</small></p>",
+        kind, description
+    )
+}
+
+/// Removes the code blocks tagged `hide-all` from the book, now that
+/// they've been tested.
+fn hide_blocks(book: &mut Book) {
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut ch) = *item {
+            ch.content = remove_hidden_blocks(&ch.content);
+        }
+    });
 }
 
 #[derive(Default)]
@@ -364,17 +3162,118 @@ impl BookKeeper {
         root: PathBuf,
         book: &mut Book,
     ) -> Result<HashMap<Test, TestResult>, Error> {
-        let config = KeeperConfig::new(preprocessor_config, &root);
+        let config = KeeperConfig::new(preprocessor_config, &root)?;
+        let (test_results, _) = self.run_with_config(&config, &*book)?;
+        finish_book(book, &test_results, &config);
+        Ok(test_results)
+    }
+
+    /// Like [`BookKeeper::real_run`], but with a config which has already
+    /// been built (say, with a [`KeeperConfigBuilder`]).
+    pub fn real_run_with_config(
+        &self,
+        config: &KeeperConfig,
+        book: &mut Book,
+    ) -> Result<HashMap<Test, TestResult>, Error> {
+        let (test_results, _) = self.run_with_config(config, &*book)?;
+        finish_book(book, &test_results, config);
+        Ok(test_results)
+    }
+
+    /// Like [`BookKeeper::real_run`], but tests the examples from any
+    /// [`TestSource`], rather than from a book.
+    pub fn real_run_with_source(
+        &self,
+        preprocessor_config: PreprocessorConfig,
+        root: PathBuf,
+        source: impl TestSource,
+    ) -> Result<HashMap<Test, TestResult>, Error> {
+        let config = KeeperConfig::new(preprocessor_config, &root)?;
+        let (test_results, _) = self.run_with_config(&config, source)?;
+        Ok(test_results)
+    }
+
+    /// Finds the tests in `source`, and if any will run, waits for the
+    /// project to build and gets the cache ready. Returns `None` if the tests
+    /// should be skipped altogether.
+    fn prepare_tests(
+        &self,
+        config: &KeeperConfig,
+        source: impl TestSource,
+        wait_for_build: impl FnOnce(&mut Timings) -> Result<(), Error>,
+        timings: &mut Timings,
+    ) -> Result<Option<Vec<Test>>, Error> {
+        let mut tests = expand_tests(source.tests(config)?, config);
+
+        // If there's nothing to run, it doesn't matter whether the
+        // project built.
+        if tests.iter().any(|test| config.will_run(test)) {
+            if !config.check_target()? {
+                return Ok(None);
+            }
+            config.check_manifest_dir()?;
+            wait_for_build(timings)?;
+            config.setup_environment()?;
+            config.fingerprint_tests(&mut tests)?;
+        }
+        Ok(Some(tests))
+    }
 
-        config.setup_environment();
+    fn run_with_config(
+        &self,
+        config: &KeeperConfig,
+        source: impl TestSource,
+    ) -> Result<(HashMap<Test, TestResult>, Timings), Error> {
+        let mut timings = Timings::default();
+        if config.clear_cache {
+            clear_cache(&config.test_dir, &config.book_name)?;
+        }
+        // Before the chapters are parsed, as that saves what's in them.
+        cache::validate_cache_index(&config.test_dir, &config.book_name)?;
 
-        let tests = get_tests_from_book(book);
+        // Building the project is slow, so it's started while the book is
+        // parsed, and waited for before any test is compiled. If it turns
+        // out that no test will run, the build is cancelled instead.
+        let cancellation = BuildCancellation::default();
+        let tests = thread::scope(|scope| {
+            let build = match (&config.manifest_dir, config.build_project) {
+                (Some(manifest_dir), true) => Some(scope.spawn(|| {
+                    let start = Instant::now();
+                    config
+                        .cargo_build(manifest_dir, &cancellation)
+                        .map(|()| start.elapsed())
+                })),
+                // The project may already have been built (say, by an earlier
+                // CI step), in which case `target_dir` is used as it is.
+                _ => None,
+            };
+            let wait_for_build = |timings: &mut Timings| -> Result<(), Error> {
+                if let Some(build) = build {
+                    timings.build =
+                        Some(build.join().unwrap_or_else(|_| {
+                            Err(Error::msg("building manifest_dir panicked"))
+                        })?);
+                }
+                Ok(())
+            };
+            let result = self.prepare_tests(config, source, wait_for_build, &mut timings);
+            // Whether or not the tests will run, the scope can't end until
+            // the build has stopped.
+            cancellation.cancel();
+            result
+        })?;
+        let tests = match tests {
+            Some(tests) => tests,
+            None => return Ok((HashMap::new(), timings)),
+        };
 
-        let test_results = run_tests_with_config(tests, &config);
+        let start = Instant::now();
+        let test_results = run_tests_with_config(tests, config, &mut timings);
+        timings.tests = start.elapsed();
 
-        cleanup_keepercache(&config, &test_results);
+        cleanup_keepercache(config, &test_results);
 
-        Ok(test_results)
+        Ok((test_results, timings))
     }
 }
 
@@ -387,8 +3286,17 @@ impl Preprocessor for BookKeeper {
         let preprocessor_config = ctx.config.get_preprocessor(self.name());
         let root = ctx.root.to_path_buf();
 
-        let test_results = self.real_run(preprocessor_config, root, &mut book)?;
-        print_results(&test_results);
+        let config = KeeperConfig::new(preprocessor_config, &root)?;
+        if config.audit {
+            print_audit(&audit_tests((&book).tests(&config)?, &config));
+            hide_blocks(&mut book);
+            return Ok(book);
+        }
+        let (test_results, timings) = self.run_with_config(&config, &book)?;
+        finish_book(&mut book, &test_results, &config);
+        let changes = record_statuses(&config, &test_results);
+        print_results(&mut io::stderr(), &test_results, &config, &timings)?;
+        print_status_changes(&changes);
 
         Ok(book)
     }
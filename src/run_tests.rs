@@ -1,83 +1,418 @@
 /// File entirely copied from:
 /// https://raw.githubusercontent.com/budziq/rust-skeptic/master/skeptic/src/rt.rs
+use std::borrow::Cow;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::OsStr;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use cargo_metadata::Edition;
+use cargo_metadata::{DependencyKind, Edition, NodeDep};
+use colored::Colorize;
 use error_chain::error_chain;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
-use crate::skeptic::Test;
+use crate::skeptic::{get_hash, line_origins, sanitize_test_name, Test};
+use crate::{Backtrace, KeeperConfig, Level};
 
 #[derive(Debug)]
 pub enum TestResult {
     Successful(Output),
     CompileFailed(Output),
     RunFailed(Output),
+    /// The test ran successfully, but didn't print the output its
+    /// `keeper-output` block expected.
+    WrongOutput(Output),
+    /// The test compiled, but took longer than its compile budget.
+    /// It isn't run.
+    CompileTooSlow(Output, Duration),
+    /// The test compiled, but its binary couldn't be executed
+    /// (for instance, because the cache is on a `noexec` mount).
+    SpawnFailed(String),
     Cached,
+    Skipped(SkipReason),
+}
+
+/// What happened to a test, without its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Successful,
+    CompileFailed,
+    RunFailed,
+    WrongOutput,
+    CompileTooSlow,
+    SpawnFailed,
+    Cached,
+    Skipped,
+}
+
+/// The reason a test was skipped by keeper, rather than
+/// by the author marking it `ignore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The code block looks like a fragment of a larger program.
+    Fragment,
+    /// With `only_failed`, the test passed last time, and its code
+    /// hasn't changed since.
+    PassedPreviously,
+    /// The block is marked `ignore-<platform>` or `<platform>-only`, and
+    /// so isn't for the platform it would be compiled for.
+    Platform,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Fragment => write!(f, "fragment"),
+            SkipReason::PassedPreviously => write!(f, "passed last time"),
+            SkipReason::Platform => write!(f, "not for this platform"),
+        }
+    }
 }
 
 impl TestResult {
+    /// What happened to the test.
+    pub fn kind(&self) -> Outcome {
+        match self {
+            TestResult::Successful(_) => Outcome::Successful,
+            TestResult::CompileFailed(_) => Outcome::CompileFailed,
+            TestResult::RunFailed(_) => Outcome::RunFailed,
+            TestResult::WrongOutput(_) => Outcome::WrongOutput,
+            TestResult::CompileTooSlow(..) => Outcome::CompileTooSlow,
+            TestResult::SpawnFailed(_) => Outcome::SpawnFailed,
+            TestResult::Cached => Outcome::Cached,
+            TestResult::Skipped(_) => Outcome::Skipped,
+        }
+    }
+
+    /// Whether the test compiled and (unless it wasn't meant to
+    /// be run) ran successfully this time. This doesn't consider
+    /// what the test expected; see [`TestResult::met_test_expectations`].
+    pub fn is_success(&self) -> bool {
+        matches!(self, TestResult::Successful(_))
+    }
+
+    /// The output of the last command run for this test (rustc, or the
+    /// test itself), if it wasn't cached or skipped, and the test could be run.
+    pub fn output(&self) -> Option<&Output> {
+        match self {
+            TestResult::Successful(output)
+            | TestResult::CompileFailed(output)
+            | TestResult::RunFailed(output)
+            | TestResult::WrongOutput(output)
+            | TestResult::CompileTooSlow(output, _) => Some(output),
+            TestResult::SpawnFailed(_) | TestResult::Cached | TestResult::Skipped(_) => None,
+        }
+    }
+
+    /// The stdout of [`TestResult::output`], with invalid UTF-8 replaced.
+    pub fn stdout(&self) -> Option<Cow<'_, str>> {
+        self.output()
+            .map(|output| String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// The stderr of [`TestResult::output`], with invalid UTF-8 replaced.
+    pub fn stderr(&self) -> Option<Cow<'_, str>> {
+        self.output()
+            .map(|output| String::from_utf8_lossy(&output.stderr))
+    }
+
     /// A test-result meets expectations if the result is
     /// what is "expected" from that test. This is either
     /// a successful test, or a crash if the test is supposed
     /// to panic. All other tests have not met expectations.
     ///
+    /// If the test expects a particular exit code, it has only met
+    /// expectations if it ran and exited with exactly that code.
+    ///
+    /// A `should_panic` test has only met expectations if it panicked
+    /// (see [`is_panic`]), rather than failing some other way. If
+    /// `should_panic_pattern` is given, its stderr must match it too.
+    ///
     /// Cached tests are assumed to have passed, since they don't
-    /// stay cached unless they pass.
-    pub fn met_test_expectations(&self, test: &Test) -> bool {
+    /// stay cached unless they pass. Skipped tests never ran, so
+    /// they can't have failed.
+    pub fn met_test_expectations(&self, test: &Test, should_panic_pattern: Option<&Regex>) -> bool {
         match self {
+            TestResult::Successful(output) | TestResult::RunFailed(output)
+                if test.expected_exit_code().is_some() =>
+            {
+                output.status.code() == test.expected_exit_code()
+            }
             TestResult::CompileFailed(_) if test.compile_fail => true,
             TestResult::Successful(_) if !test.should_panic => true,
-            TestResult::RunFailed(_) if test.should_panic => true,
+            TestResult::RunFailed(output)
+                if test.should_panic && is_panic(output) && !is_sanitizer_error(output) =>
+            {
+                match should_panic_pattern {
+                    Some(pattern) => panic_message_matches(output, pattern),
+                    None => true,
+                }
+            }
             TestResult::Cached => true,
+            TestResult::Skipped(_) => true,
             _ => false,
         }
     }
 }
 
-/// This function is designed to run a single test.
+/// Whether the stderr of a test which panicked matches `pattern`.
+pub fn panic_message_matches(output: &Output, pattern: &Regex) -> bool {
+    pattern.is_match(&String::from_utf8_lossy(&output.stderr))
+}
+
+/// The signal Rust aborts with, when panics abort rather than unwind.
+const SIGABRT: i32 = 6;
+
+/// Whether a failed run was a panic: it exited with the code Rust uses
+/// for one (101), or, if panics abort, it was killed by `SIGABRT`.
+pub fn is_panic(output: &Output) -> bool {
+    output.status.code() == Some(101) || exit_signal(output) == Some(SIGABRT)
+}
+
+/// The signal which killed the test, if it was killed by one. Only
+/// Unix has signals.
+pub fn exit_signal(output: &Output) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        output.status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = output;
+        None
+    }
+}
+
+/// How a failed run ended, like `Panicked` or `Exited with code 1`.
+pub fn describe_exit(output: &Output) -> String {
+    if is_panic(output) {
+        return String::from("Panicked");
+    }
+    match (output.status.code(), exit_signal(output)) {
+        (Some(code), _) => format!("Exited with code {}", code),
+        (None, Some(signal)) => format!("Killed by signal {}", signal),
+        (None, None) => String::from("Failed"),
+    }
+}
+
+/// Whether a failed run was caused by a sanitizer detecting an error,
+/// rather than (for instance) a panic.
+pub fn is_sanitizer_error(output: &Output) -> bool {
+    String::from_utf8_lossy(&output.stderr).contains("Sanitizer: ")
+}
+
+/// The error codes (like `E0308`) rustc reported in `output`, sorted
+/// and without duplicates.
+pub fn error_codes(output: &Output) -> Vec<String> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut codes = stderr
+        .match_indices("error[E")
+        .filter_map(|(start, _)| {
+            let code = stderr[start + 6..].split(']').next()?;
+            if code.len() == 5 && code[1..].chars().all(|c| c.is_ascii_digit()) {
+                Some(code.to_string())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    codes.sort();
+    codes.dedup();
+    codes
+}
+
+/// A test which compiled successfully, and is ready to be run
+/// with [`run_compiled`].
+#[derive(Debug)]
+pub struct CompiledTest {
+    /// The binary rustc produced. Nothing is produced for
+    /// [`CompileType::Check`].
+    pub binary_path: PathBuf,
+    /// The directory the binary should be run in.
+    pub working_dir: PathBuf,
+    pub compile_type: CompileType,
+    /// What rustc printed while compiling the test.
+    pub compile_output: Output,
+    /// How long rustc took to compile the test.
+    pub compile_time: Duration,
+}
+
+/// How long a test took to run, when it was run `run_iterations` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunTimes {
+    pub iterations: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub max: Duration,
+}
+
+impl RunTimes {
+    /// The times of `durations`, which mustn't be empty.
+    fn from_durations(mut durations: Vec<Duration>) -> RunTimes {
+        durations.sort();
+        RunTimes {
+            iterations: durations.len(),
+            min: durations[0],
+            median: durations[durations.len() / 2],
+            max: durations[durations.len() - 1],
+        }
+    }
+}
+
+/// This function is designed to run a single test, by compiling it
+/// with [`compile_test`] and then running it with [`run_compiled_timed`].
+/// If it was run more than once, how long it took is returned too.
 ///
-///  - `manifest_dir` should be a path to a `Cargo.toml`.
-///  - `target_dir` should be the path the root of a "target" directory.
+///  - `config` is the configuration keeper is running with.
+///  - `test` is the test being run.
 ///  - `target_triple` should be the type of the target to compile.
 ///  - `testcase_path` should be the path to a rust file, which contains the test code.
 ///  - `compile_type` should be [`CompileType::Full`] if the compilation should include
-///    running the code; otherwise just [`CompileType::Check`]
+///    running the code, [`CompileType::Test`] if it should be run with the test harness,
+///    [`CompileType::Link`] if it should be linked but not run; otherwise just
+///    [`CompileType::Check`]
 pub fn handle_test(
-    manifest_dir: Option<&Path>,
-    target_dir: &Path,
+    config: &KeeperConfig,
+    test: &Test,
+    target_triple: &str,
+    testcase_path: &Path,
+    compile_type: CompileType,
+) -> (TestResult, Option<RunTimes>) {
+    let (result, run_times) =
+        match compile_test(config, test, target_triple, testcase_path, compile_type) {
+            Ok(compiled) => match config.compile_budget(test) {
+                Some(budget) if compiled.compile_time > budget => {
+                    if config.compile_budget_level == Level::Deny {
+                        return (
+                            TestResult::CompileTooSlow(
+                                compiled.compile_output,
+                                compiled.compile_time,
+                            ),
+                            None,
+                        );
+                    }
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Warning: {} took {}ms to compile, over its budget of {}ms",
+                            test.name,
+                            compiled.compile_time.as_millis(),
+                            budget.as_millis()
+                        )
+                        .yellow()
+                    );
+                    run_compiled_timed(&compiled, config)
+                }
+                _ => run_compiled_timed(&compiled, config),
+            },
+            Err(result) => (result, None),
+        };
+
+    // Tests which weren't run have no output to check.
+    let result = match (result, &test.expected_output) {
+        (TestResult::Successful(output), Some(expected))
+            if !matches!(compile_type, CompileType::Check | CompileType::Link)
+                && normalize_output(&String::from_utf8_lossy(&output.stdout))
+                    != normalize_output(expected) =>
+        {
+            TestResult::WrongOutput(output)
+        }
+        (result, _) => result,
+    };
+    (result, run_times)
+}
+
+/// The edition to compile `test` with: the one its block gives, or else
+/// the configured one, or else the one found from `manifest_dir` (2021
+/// if none of its packages has an edition keeper knows). If there's no
+/// `manifest_dir`, rustc's default (2015) is used.
+fn test_edition(test: &Test, config: &KeeperConfig) -> Option<String> {
+    if let Some(edition) = test.edition.as_ref().or(config.edition.as_ref()) {
+        return Some(edition.clone());
+    }
+
+    let manifest_dir = config.manifest_dir.as_ref()?;
+    // Found when the environment was set up (which fails if it can't
+    // be), so cargo isn't asked again.
+    let edition = config.project_edition(manifest_dir).ok()?;
+    Some(edition.unwrap_or(DEFAULT_EDITION).to_string())
+}
+
+/// `output` without trailing whitespace on each line, or trailing
+/// blank lines, which are hard to see in the book.
+pub fn normalize_output(output: &str) -> String {
+    output
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+/// Compiles a single test, without running it. The arguments are
+/// the same as [`handle_test`]'s. If the test doesn't compile, the
+/// `Err` is the [`TestResult`] for the test.
+pub fn compile_test(
+    config: &KeeperConfig,
+    test: &Test,
     target_triple: &str,
     testcase_path: &Path,
     compile_type: CompileType,
-    terminal_colors: bool,
-    externs: &Vec<String>,
-) -> TestResult {
+) -> std::result::Result<CompiledTest, TestResult> {
     // First, let's get the command ready, no matter
     // whether or not a Cargo.toml is specified.
 
-    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
-    let mut cmd = Command::new(rustc);
+    let mut cmd = rustc_command(config);
     cmd.arg(testcase_path)
         .arg("--verbose")
-        .arg(if terminal_colors {
+        .arg(if config.terminal_colors {
             "--color=always"
         } else {
             "--color=never"
         });
 
     match compile_type {
-        CompileType::Full => cmd.arg("--crate-type=bin"),
+        CompileType::Full | CompileType::Link => cmd.arg("--crate-type=bin"),
         CompileType::Check => cmd.arg("--crate-type=lib"),
+        CompileType::Test => cmd.arg("--test"),
     };
 
-    if let Some(manifest_dir) = manifest_dir {
+    if let Some(sanitizer) = &config.sanitizer {
+        // Sanitizers need an explicit target, which is otherwise only
+        // passed when there's a manifest.
+        cmd.arg(format!("-Zsanitizer={}", sanitizer));
+        if config.manifest_dir.is_none() && config.prebuilt_deps.is_none() {
+            cmd.arg("--target").arg(target_triple);
+        }
+    }
+
+    if let Some(edition) = test_edition(test, config) {
+        cmd.arg(format!("--edition={}", edition));
+    }
+
+    if config.incremental {
+        // Keyed by name rather than hash, so that edits to a test
+        // can reuse the work done compiling the previous version. The
+        // crate is named the same way, as rustc would otherwise name it
+        // after the file (and so the hash), and start afresh.
+        let name = sanitize_test_name(&test.name);
+        let incremental_dir = config.test_dir.join("incremental").join(&name);
+        cmd.arg("-C")
+            .arg(format!("incremental={}", incremental_dir.display()));
+        cmd.arg(format!("--crate-name=keeper_{}", name));
+    }
+
+    if let Some(manifest_dir) = &config.manifest_dir {
         // OK, here's where a bunch of magic happens using assumptions
         // about cargo internals. We are going to use rustc to compile
         // the examples, but to do that we've got to tell it where to
@@ -89,22 +424,9 @@ pub fn handle_test(
         let mut cargo_toml_path = PathBuf::from(manifest_dir);
         cargo_toml_path.push("Cargo.toml");
 
-        let mut deps_dir = PathBuf::from(target_dir);
-        deps_dir.push("debug/deps");
-
-        // Find the edition
-
-        // This has to come before "-L".
-        let metadata = get_cargo_meta(&cargo_toml_path).expect("failed to read Cargo.toml");
-        let edition = metadata
-            .packages
-            .iter()
-            .filter_map(|package| edition_str(&package.edition))
-            .max()
-            .unwrap();
-        if edition != "2015" {
-            cmd.arg(format!("--edition={}", edition));
-        }
+        let target_dir = &config.target_dir;
+        let profile_dir = find_profile_dir(target_dir, target_triple);
+        let deps_dir = profile_dir.join("deps");
 
         cmd.arg("-L")
             .arg(target_dir)
@@ -113,69 +435,663 @@ pub fn handle_test(
             .arg("--target")
             .arg(target_triple);
 
-        for dep in externs {
-            cmd.arg("--extern");
-            cmd.arg(dep);
+        // Examples may `include!` files generated by the package's build
+        // script, as they can under `cargo test`.
+        if let Some(out_dir) = find_out_dir(&cargo_toml_path, &profile_dir) {
+            cmd.env("OUT_DIR", out_dir);
         }
 
-        for dep in get_rlib_dependencies(manifest_dir.to_path_buf(), target_dir.to_path_buf())
-            .expect("failed to read dependencies")
-        {
-            cmd.arg("--extern");
-            cmd.arg(format!(
-                "{}={}",
-                dep.libname,
-                dep.rlib.to_str().expect("filename not utf8"),
-            ));
+        let deps = get_rlib_dependencies(
+            manifest_dir.clone(),
+            profile_dir,
+            config.lockfile.as_deref(),
+            config.include_dev_dependencies,
+        )
+        .expect("failed to read dependencies")
+        .found;
+
+        let libs = deps
+            .into_iter()
+            .map(|dep| (dep.libname, dep.rlib))
+            .collect::<BTreeMap<_, _>>();
+        add_externs(&mut cmd, &config.externs, &libs);
+    } else if let Some(deps) = &config.prebuilt_deps {
+        // The libraries were built by something other than cargo, and
+        // listed in `deps_manifest`. Their own dependencies are looked
+        // for alongside them.
+        let mut lib_dirs = deps
+            .values()
+            .filter_map(|rlib| rlib.parent())
+            .collect::<Vec<_>>();
+        lib_dirs.sort();
+        lib_dirs.dedup();
+        for lib_dir in lib_dirs {
+            cmd.arg("-L")
+                .arg(format!("dependency={}", lib_dir.display()));
         }
+        cmd.arg("--target").arg(target_triple);
+
+        add_externs(&mut cmd, &config.externs, deps);
     }
 
     let mut binary_path = PathBuf::from(testcase_path);
     binary_path.set_extension("exe");
 
     match compile_type {
-        CompileType::Full => cmd.arg("-o").arg(&binary_path),
+        CompileType::Full | CompileType::Test | CompileType::Link => {
+            cmd.arg("-o").arg(&binary_path)
+        }
         CompileType::Check => cmd.arg(format!(
             "--emit=dep-info={0}.d,metadata={0}.m",
             binary_path.display()
         )),
     };
 
-    let command_result = cmd.output().unwrap();
-    return if !command_result.status.success() {
-        TestResult::CompileFailed(command_result)
-    } else if CompileType::Check == compile_type {
-        TestResult::Successful(command_result)
-    } else {
-        let cmd_current_dir = testcase_path
+    if config.diagnostics_path.is_some() {
+        cmd.arg("--error-format=json");
+        if config.terminal_colors {
+            cmd.arg("--json=diagnostic-rendered-ansi");
+        }
+    }
+
+    let started = Instant::now();
+    let mut command_result = cmd.output().unwrap();
+    let compile_time = started.elapsed();
+    if let Some(diagnostics_path) = &config.diagnostics_path {
+        record_diagnostics(&mut command_result, test, config, diagnostics_path);
+    }
+    if !command_result.status.success() {
+        return Err(TestResult::CompileFailed(command_result));
+    }
+
+    // With a fixture, each test gets its own directory to run in,
+    // so tests can't see changes other tests made to the fixture.
+    let working_dir = match &config.fixture_dir {
+        Some(_) => config.test_dir.join("run").join(config.file_hash(test)),
+        None => testcase_path
             .parent()
-            .expect("File must live in a directory.");
+            .expect("File must live in a directory.")
+            .to_path_buf(),
+    };
 
-        let mut cmd = Command::new(binary_path);
-        cmd.current_dir(cmd_current_dir);
-        let command_output = cmd.output().unwrap();
+    Ok(CompiledTest {
+        binary_path,
+        working_dir,
+        compile_type,
+        compile_output: command_result,
+        compile_time,
+    })
+}
 
-        if command_output.status.success() {
-            TestResult::Successful(command_result)
-        } else {
-            TestResult::RunFailed(command_result)
+/// Replaces rustc's JSON diagnostics in `output` with the text rustc
+/// would otherwise have printed, and appends each diagnostic to
+/// `diagnostics_path`, along with where in the book it is.
+fn record_diagnostics(
+    output: &mut Output,
+    test: &Test,
+    config: &KeeperConfig,
+    diagnostics_path: &Path,
+) {
+    let chapter_lines = chapter_lines(test, config);
+    let chapter_line = |line: u64| *chapter_lines.get((line as usize).checked_sub(1)?)?;
+
+    let mut rendered = String::new();
+    let mut records = String::new();
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        let mut diagnostic = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(diagnostic) if diagnostic["$message_type"] == "diagnostic" => diagnostic,
+            // Anything else rustc printed is kept as it is.
+            _ => {
+                rendered.push_str(line);
+                rendered.push('\n');
+                continue;
+            }
+        };
+        if let Some(text) = diagnostic["rendered"].as_str() {
+            rendered.push_str(text);
+        }
+        add_chapter_lines(&mut diagnostic, &chapter_line);
+
+        let record = serde_json::json!({
+            "test": test.name,
+            "file": test.file,
+            "line": test.line,
+            "diagnostic": diagnostic,
+        });
+        records.push_str(&record.to_string());
+        records.push('\n');
+    }
+    output.stderr = rendered.into_bytes();
+
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(diagnostics_path)
+        .and_then(|mut file| file.write_all(records.as_bytes()));
+    if let Err(e) = written {
+        eprintln!(
+            "{}",
+            format!(
+                "Warning: couldn't write diagnostics to {}: {}",
+                diagnostics_path.display(),
+                e
+            )
+            .yellow()
+        );
+    }
+}
+
+/// For each line of the file written for `test`, the line of the chapter
+/// it came from, if it came from this test's block (rather than the
+/// wrapper, or an earlier block's prelude).
+pub fn chapter_lines(test: &Test, config: &KeeperConfig) -> Vec<Option<usize>> {
+    let lines = [&test.prelude[..], &test.text[..]].concat();
+    line_origins(
+        &lines,
+        &config.wrapper_template,
+        config.rustdoc_compat,
+        config.main_wrapper.as_ref(),
+    )
+    .into_iter()
+    .map(|origin| {
+        let index = origin?.checked_sub(test.prelude.len())?;
+        Some(test.line + index)
+    })
+    .collect()
+}
+
+/// Adds `chapter_line_start` and `chapter_line_end` to every span of
+/// `diagnostic` (and of its children), giving the lines of the chapter
+/// the span is on. They're `null` for code that isn't in the chapter.
+fn add_chapter_lines(
+    diagnostic: &mut serde_json::Value,
+    chapter_line: &dyn Fn(u64) -> Option<usize>,
+) {
+    if let Some(spans) = diagnostic["spans"].as_array_mut() {
+        for span in spans {
+            let start = span["line_start"].as_u64().and_then(chapter_line);
+            let end = span["line_end"].as_u64().and_then(chapter_line);
+            span["chapter_line_start"] = serde_json::json!(start);
+            span["chapter_line_end"] = serde_json::json!(end);
+        }
+    }
+    if let Some(children) = diagnostic["children"].as_array_mut() {
+        for child in children {
+            add_chapter_lines(child, chapter_line);
+        }
+    }
+}
+
+/// A command running rustc (or `RUSTC`) to compile examples, through the
+/// wrappers cargo would use: `RUSTC_WRAPPER` and `RUSTC_WORKSPACE_WRAPPER`,
+/// in that order, unless `rustc_wrapper` is set. This lets tools like
+/// sccache cache examples as they do the rest of the project.
+fn rustc_command(config: &KeeperConfig) -> Command {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let wrappers = match &config.rustc_wrapper {
+        Some(wrapper) => vec![wrapper.clone()],
+        None => ["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .collect(),
+    };
+
+    // Like cargo, an empty wrapper means there isn't one.
+    let mut programs = wrappers
+        .into_iter()
+        .filter(|wrapper| !wrapper.is_empty())
+        .chain([rustc]);
+    let mut cmd = Command::new(programs.next().unwrap());
+    cmd.args(programs);
+    cmd
+}
+
+/// Whether the standard library for `target` is installed, so examples
+/// can be compiled for it. If this can't be worked out (for instance,
+/// because `target` is a custom target file), it's assumed to be.
+pub fn target_is_installed(target: &str) -> bool {
+    if target.ends_with(".json") {
+        return true;
+    }
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let output = match Command::new(rustc).args(["--print", "sysroot"]).output() {
+        Ok(output) if output.status.success() => output,
+        _ => return true,
+    };
+    let sysroot = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+    sysroot
+        .join("lib")
+        .join("rustlib")
+        .join(target)
+        .join("lib")
+        .is_dir()
+}
+
+/// Runs a test compiled by [`compile_test`]. Tests which were only
+/// checked or linked aren't run, and are successful.
+pub fn run_compiled(compiled: &CompiledTest, config: &KeeperConfig) -> TestResult {
+    run_compiled_timed(compiled, config).0
+}
+
+/// Like [`run_compiled`], but also gives how long the test took to run,
+/// if `run_iterations` is more than 1. The test is run that many times,
+/// and the first run which doesn't succeed is the result (with no times).
+pub fn run_compiled_timed(
+    compiled: &CompiledTest,
+    config: &KeeperConfig,
+) -> (TestResult, Option<RunTimes>) {
+    if let CompileType::Check | CompileType::Link = compiled.compile_type {
+        return (
+            TestResult::Successful(compiled.compile_output.clone()),
+            None,
+        );
+    }
+
+    let iterations = config.run_iterations.max(1) as usize;
+    let mut durations = Vec::new();
+    loop {
+        let started = Instant::now();
+        let result = run_binary(compiled, config);
+        durations.push(started.elapsed());
+
+        match result {
+            TestResult::Successful(_) if durations.len() < iterations => {}
+            TestResult::Successful(_) if iterations > 1 => {
+                return (result, Some(RunTimes::from_durations(durations)))
+            }
+            result => return (result, None),
+        }
+    }
+}
+
+/// Runs the binary of a compiled test once.
+fn run_binary(compiled: &CompiledTest, config: &KeeperConfig) -> TestResult {
+    if let Some(fixture_dir) = &config.fixture_dir {
+        if let Err(e) = copy_fixture(fixture_dir, &compiled.working_dir) {
+            return TestResult::SpawnFailed(format!("could not copy fixture_dir: {}", e));
+        }
+    }
+
+    let mut cmd = Command::new(&compiled.binary_path);
+    cmd.current_dir(&compiled.working_dir);
+    if let Some(locale) = &config.locale {
+        cmd.env("LC_ALL", locale);
+    }
+    if let Some(timezone) = &config.timezone {
+        cmd.env("TZ", timezone);
+    }
+    if let Some(seed) = config.seed {
+        cmd.env("KEEPER_SEED", seed.to_string());
+    }
+    // So that the output of an example which panics says where, unless
+    // whoever ran keeper already chose how much of a backtrace they want.
+    match config.backtrace {
+        Some(backtrace) => {
+            cmd.env("RUST_BACKTRACE", backtrace.env_value());
+        }
+        None if env::var_os("RUST_BACKTRACE").is_none() => {
+            cmd.env("RUST_BACKTRACE", Backtrace::default().env_value());
+        }
+        None => {}
+    }
+    if CompileType::Test == compiled.compile_type && config.run_ignored_tests {
+        cmd.arg("--include-ignored");
+    }
+    let command_output = cmd.output();
+    if config.fixture_dir.is_some() {
+        // Whatever the test did to its copy of the fixture isn't needed.
+        let _ = fs::remove_dir_all(&compiled.working_dir);
+    }
+    let mut command_output = match command_output {
+        Ok(output) => output,
+        Err(e) => return TestResult::SpawnFailed(e.to_string()),
+    };
+
+    // The status and stdout are the binary's, but rustc's warnings about
+    // an example are still worth showing. A harness prints its own report,
+    // which is all that's useful.
+    if CompileType::Full == compiled.compile_type {
+        let mut stderr = compiled.compile_output.stderr.clone();
+        stderr.append(&mut command_output.stderr);
+        command_output.stderr = stderr;
+    }
+    let allowed_exit = matches!(
+        command_output.status.code(),
+        Some(code) if config.allowed_exit_codes.contains(&code)
+    );
+    if command_output.status.success() || allowed_exit {
+        TestResult::Successful(command_output)
+    } else {
+        TestResult::RunFailed(command_output)
+    }
+}
+
+/// Replaces `working_dir` with a fresh copy of the contents of `fixture_dir`.
+fn copy_fixture(fixture_dir: &Path, working_dir: &Path) -> std::result::Result<(), String> {
+    if working_dir.exists() {
+        fs::remove_dir_all(working_dir).map_err(|e| e.to_string())?;
+    }
+    fs::create_dir_all(working_dir).map_err(|e| e.to_string())?;
+
+    let items = fs::read_dir(fixture_dir)
+        .map_err(|e| e.to_string())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    fs_extra::copy_items(&items, working_dir, &fs_extra::dir::CopyOptions::new())
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// A hash of the names and contents of every file in `fixture_dir`,
+/// so tests can be re-run when the fixture changes.
+pub fn fixture_fingerprint(fixture_dir: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for entry in WalkDir::new(fixture_dir).sort_by_file_name() {
+        let entry = entry.map_err(std::io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let relative = path.strip_prefix(fixture_dir).unwrap_or(path);
+        hasher.update(relative.display().to_string().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(path)?);
+    }
+
+    Ok(base64_url::encode(hasher.finalize().as_slice()))
+}
+
+/// The pattern an entry in `externs` like `myorg-*` stands for, if it's
+/// a glob rather than a crate name. Dashes are treated as underscores,
+/// as in crate names.
+pub fn extern_glob(extern_: &str) -> Option<glob::Pattern> {
+    if extern_.contains('=') || !extern_.contains(['*', '?', '[']) {
+        return None;
+    }
+    glob::Pattern::new(&extern_.replace('-', "_")).ok()
+}
+
+/// `externs`, with each glob replaced by the names in `names` it
+/// matches. Other entries are left as they are.
+pub fn expand_externs(externs: &[String], names: &[String]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for extern_ in externs {
+        match extern_glob(extern_) {
+            Some(pattern) => {
+                let mut matching = names
+                    .iter()
+                    .filter(|name| pattern.matches(name))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                matching.sort();
+                expanded.extend(matching);
+            }
+            None => expanded.push(extern_.clone()),
+        }
+    }
+    expanded.dedup();
+    expanded
+}
+
+/// Splits an entry in `externs` of the form `alias=crate` into the
+/// alias and the name of the crate. Entries like `name=path/to/lib.rlib`
+/// (or just `name`) aren't aliases, and give `None`.
+pub fn parse_extern_alias(extern_: &str) -> Option<(&str, &str)> {
+    let (alias, krate) = extern_.split_once('=')?;
+    if krate
+        .chars()
+        .all(|c| c == '_' || c == '-' || c.is_alphanumeric())
+    {
+        Some((alias, krate))
+    } else {
+        None
+    }
+}
+
+/// Passes rustc each library in `libs` (keyed by library name), and
+/// each entry in `externs`. An `alias=crate` entry gives the alias the
+/// library of `crate`.
+fn add_externs(cmd: &mut Command, externs: &[String], libs: &BTreeMap<String, PathBuf>) {
+    let libnames = libs.keys().cloned().collect::<Vec<_>>();
+    for dep in &expand_externs(externs, &libnames) {
+        cmd.arg("--extern");
+        match parse_extern_alias(dep)
+            .and_then(|(alias, krate)| Some((alias, libs.get(&krate.replace('-', "_"))?)))
+        {
+            Some((alias, rlib)) => cmd.arg(format!("{}={}", alias, rlib.display())),
+            None => cmd.arg(dep),
+        };
+    }
+
+    for (libname, rlib) in libs {
+        cmd.arg("--extern")
+            .arg(format!("{}={}", libname, rlib.display()));
+    }
+}
+
+/// Finds the directory cargo put the build artifacts in. When cargo
+/// is given `--target`, it uses `<target_dir>/<triple>/debug`, rather
+/// than `<target_dir>/debug`.
+fn find_profile_dir(target_dir: &Path, target_triple: &str) -> PathBuf {
+    let triple_dir = target_dir.join(target_triple).join("debug");
+    if triple_dir.is_dir() {
+        triple_dir
+    } else {
+        target_dir.join("debug")
+    }
+}
+
+/// Finds the `OUT_DIR` cargo gave the build script of the package at
+/// `cargo_toml_path`, if it has one. If cargo has run the build script
+/// more than once (say, with different features), the newest is used.
+fn find_out_dir(cargo_toml_path: &Path, profile_dir: &Path) -> Option<PathBuf> {
+    let metadata = get_cargo_meta(cargo_toml_path).ok()?;
+    let prefix = format!("{}-", metadata.root_package()?.name);
+
+    fs::read_dir(profile_dir.join("build"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| match entry.file_name().to_str() {
+            Some(name) => matches!(
+                name.strip_prefix(&prefix),
+                Some(hash) if hash.chars().all(|c| c.is_ascii_hexdigit())
+            ),
+            None => false,
+        })
+        .map(|entry| entry.path().join("out"))
+        .filter(|out_dir| out_dir.is_dir())
+        .max_by_key(|out_dir| fs::metadata(out_dir).and_then(|m| m.modified()).ok())
+}
+
+/// The names of the crates examples can use from the project in
+/// `manifest_dir`: the dependencies of its packages, and the packages
+/// themselves.
+pub fn dependency_names(manifest_dir: &Path, dev_dependencies: bool) -> Result<Vec<String>> {
+    let metadata = get_cargo_meta(manifest_dir.join("Cargo.toml"))?;
+    let resolve = metadata.resolve.ok_or("Missing dependency metadata")?;
+
+    let mut names = resolve
+        .nodes
+        .into_iter()
+        .filter(|node| metadata.workspace_members.contains(&node.id))
+        .flat_map(|node| node.deps.into_iter())
+        .filter(|dep| is_usable_dependency(dep, dev_dependencies))
+        .map(|dep| dep.name)
+        .chain(
+            metadata
+                .packages
+                .iter()
+                .filter(|package| metadata.workspace_members.contains(&package.id))
+                .map(|package| package.name.replace('-', "_")),
+        )
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    Ok(names)
+}
+
+/// A hash of the project's dependencies: its `Cargo.lock`, and the
+/// libraries examples are linked against. If a dependency changes,
+/// so does this.
+pub fn project_fingerprint(
+    manifest_dir: &Path,
+    target_dir: &Path,
+    target_triple: &str,
+    lockfile: Option<&Path>,
+    dev_dependencies: bool,
+) -> Result<String> {
+    let lockfile_path = match lockfile {
+        Some(lockfile) => lockfile.to_path_buf(),
+        None => {
+            let metadata = get_cargo_meta(manifest_dir.join("Cargo.toml"))?;
+            metadata.workspace_root.join("Cargo.lock").into()
         }
     };
+    let lockfile_contents = fs::read_to_string(lockfile_path).unwrap_or_default();
+
+    let profile_dir = find_profile_dir(target_dir, target_triple);
+    let mut rlibs = get_rlib_dependencies(
+        manifest_dir.to_path_buf(),
+        profile_dir,
+        lockfile,
+        dev_dependencies,
+    )?
+    .found
+    .into_iter()
+    // The path stays the same when a path dependency's source is edited,
+    // so the time it was built is hashed too.
+    .map(|dep| {
+        let modified = fs::metadata(&dep.rlib).and_then(|m| m.modified()).ok();
+        format!("{} {:?}", dep.rlib.display(), modified)
+    })
+    .collect::<Vec<_>>();
+    rlibs.sort();
+
+    Ok(get_hash(&format!(
+        "{}\n{}",
+        lockfile_contents,
+        rlibs.join("\n")
+    )))
+}
+
+/// Reads a `deps_manifest`: a JSON object from crate names to the
+/// libraries examples can use, for projects which cargo doesn't build.
+/// Relative paths are relative to the manifest.
+pub fn read_deps_manifest(path: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let libraries: BTreeMap<String, PathBuf> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let manifest_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(libraries
+        .into_iter()
+        .map(|(name, rlib)| (name.replace('-', "_"), manifest_dir.join(rlib)))
+        .collect())
+}
+
+/// A hash of the libraries from a `deps_manifest`, which changes when
+/// one of them is rebuilt, as `project_fingerprint` does for cargo's.
+pub fn prebuilt_fingerprint(deps: &BTreeMap<String, PathBuf>) -> Result<String> {
+    let mut libraries = String::new();
+    for (libname, rlib) in deps {
+        let modified = fs::metadata(rlib)?.modified()?;
+        libraries.push_str(&format!("{}={} {:?}\n", libname, rlib.display(), modified));
+    }
+
+    Ok(get_hash(&libraries))
+}
+
+/// The dependencies of the project which examples can link against.
+struct Dependencies {
+    found: Vec<Fingerprint>,
+    /// Locked dependencies which cargo built a library for, but
+    /// whose artifact couldn't be found.
+    missing: Vec<String>,
+}
+
+/// Names the dependencies of the project in `manifest_dir` which were
+/// built, but whose library can't be found, so examples can't use them.
+pub fn missing_dependency_artifacts(
+    manifest_dir: &Path,
+    target_dir: &Path,
+    target_triple: &str,
+    lockfile: Option<&Path>,
+    dev_dependencies: bool,
+) -> Result<Vec<String>> {
+    let profile_dir = find_profile_dir(target_dir, target_triple);
+    Ok(get_rlib_dependencies(
+        manifest_dir.to_path_buf(),
+        profile_dir,
+        lockfile,
+        dev_dependencies,
+    )?
+    .missing)
+}
+
+/// The version of each crate examples can use from the project in
+/// `manifest_dir`, keyed by crate name, as they were resolved for the build.
+pub fn dependency_versions(
+    manifest_dir: &Path,
+    lockfile: Option<&Path>,
+    dev_dependencies: bool,
+) -> Result<BTreeMap<String, String>> {
+    Ok(locked_versions(manifest_dir, lockfile, dev_dependencies)?
+        .into_iter()
+        .collect())
+}
+
+/// The locked version of each dependency examples can use, keyed by
+/// library name.
+fn locked_versions(
+    manifest_dir: &Path,
+    lockfile: Option<&Path>,
+    dev_dependencies: bool,
+) -> Result<HashMap<String, String>> {
+    let lock = LockedDeps::from_path(manifest_dir, dev_dependencies)?;
+
+    let mut locked_deps: HashMap<String, String> = lock.collect();
+    // An explicit lockfile decides which version of each dependency
+    // is used, rather than the one cargo's metadata found.
+    if let Some(lockfile) = lockfile {
+        for (name, version) in read_lockfile_versions(lockfile)? {
+            if let Some(locked_ver) = locked_deps.get_mut(&name) {
+                *locked_ver = version;
+            }
+        }
+    }
+
+    Ok(locked_deps)
 }
 
 // Retrieve the exact dependencies for a given build by
 // cross-referencing the lockfile with the fingerprint file
-fn get_rlib_dependencies(manifest_dir: PathBuf, target_dir: PathBuf) -> Result<Vec<Fingerprint>> {
-    let lock = LockedDeps::from_path(manifest_dir)?;
+fn get_rlib_dependencies(
+    manifest_dir: PathBuf,
+    profile_dir: PathBuf,
+    lockfile: Option<&Path>,
+    dev_dependencies: bool,
+) -> Result<Dependencies> {
+    let locked_deps = locked_versions(&manifest_dir, lockfile, dev_dependencies)?;
 
-    let fingerprint_dir = target_dir.join(".fingerprint/");
-    let locked_deps: HashMap<String, String> = lock.collect();
+    let fingerprint_dir = profile_dir.join(".fingerprint/");
     let mut found_deps: HashMap<String, Fingerprint> = HashMap::new();
+    let mut without_artifact = Vec::new();
 
-    for finger in WalkDir::new(fingerprint_dir)
+    for fingerprint in WalkDir::new(fingerprint_dir)
         .into_iter()
-        .filter_map(|v| Fingerprint::from_path(v.ok()?.path()).ok())
+        .filter_map(|v| Some(Fingerprint::from_path(v.ok()?.path())))
     {
+        let finger = match fingerprint {
+            Ok(finger) => finger,
+            Err(Error(ErrorKind::MissingArtifact(libname), _)) => {
+                without_artifact.push(libname);
+                continue;
+            }
+            Err(_) => continue,
+        };
         let locked_ver = match locked_deps.get(&finger.name()) {
             Some(ver) => ver,
             None => continue,
@@ -185,8 +1101,9 @@ fn get_rlib_dependencies(manifest_dir: PathBuf, target_dir: PathBuf) -> Result<V
         match (found_deps.entry(finger.name()), finger.version()) {
             (Entry::Occupied(mut e), Some(ver)) => {
                 // we find better match only if it is exact version match
-                // and has fresher build time
-                if *locked_ver == ver && e.get().mtime < finger.mtime {
+                // and has fresher build time, or the match so far had no version
+                if *locked_ver == ver && (e.get().version.is_none() || e.get().mtime < finger.mtime)
+                {
                     e.insert(finger);
                 }
             }
@@ -200,9 +1117,51 @@ fn get_rlib_dependencies(manifest_dir: PathBuf, target_dir: PathBuf) -> Result<V
         }
     }
 
-    Ok(found_deps
+    let mut missing = without_artifact
+        .into_iter()
+        .filter(|libname| locked_deps.contains_key(libname) && !found_deps.contains_key(libname))
+        .collect::<Vec<_>>();
+    missing.sort();
+    missing.dedup();
+
+    Ok(Dependencies {
+        found: found_deps
+            .into_iter()
+            .filter_map(|(_, val)| if val.rlib.exists() { Some(val) } else { None })
+            .collect(),
+        missing,
+    })
+}
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+}
+
+/// The version of each package in the `Cargo.lock` at `path`, keyed by
+/// library name. Packages locked at several versions are left out, since
+/// which one is meant can't be told from the name alone.
+fn read_lockfile_versions(path: &Path) -> Result<HashMap<String, String>> {
+    let lockfile: Lockfile = toml::from_str(&fs::read_to_string(path)?)?;
+
+    let mut versions: HashMap<String, Option<String>> = HashMap::new();
+    for package in lockfile.package {
+        versions
+            .entry(package.name.replace('-', "_"))
+            .and_modify(|version| *version = None)
+            .or_insert(Some(package.version));
+    }
+
+    Ok(versions
         .into_iter()
-        .filter_map(|(_, val)| if val.rlib.exists() { Some(val) } else { None })
+        .filter_map(|(name, version)| Some((name, version?)))
         .collect())
 }
 
@@ -221,7 +1180,7 @@ fn get_cargo_meta<P: AsRef<Path> + std::convert::AsRef<std::ffi::OsStr>>(
 }
 
 impl LockedDeps {
-    fn from_path<P: AsRef<Path>>(path: P) -> Result<LockedDeps> {
+    fn from_path<P: AsRef<Path>>(path: P, dev_dependencies: bool) -> Result<LockedDeps> {
         let path = path.as_ref().join("Cargo.toml");
         let metadata = get_cargo_meta(path)?;
         let workspace_members = metadata.workspace_members;
@@ -231,7 +1190,9 @@ impl LockedDeps {
             .nodes
             .into_iter()
             .filter(|node| workspace_members.contains(&node.id))
-            .flat_map(|node| node.dependencies.into_iter())
+            .flat_map(|node| node.deps.into_iter())
+            .filter(|dep| is_usable_dependency(dep, dev_dependencies))
+            .map(|dep| dep.pkg)
             .chain(workspace_members.clone());
 
         Ok(LockedDeps {
@@ -240,15 +1201,52 @@ impl LockedDeps {
     }
 }
 
+/// Whether examples can use `dep`. Normal dependencies can be used, and
+/// dev-dependencies can be if `dev_dependencies` is set. Build-dependencies
+/// are built for the build script, so they can't be. Old versions of cargo
+/// don't say what kind a dependency is, so those are assumed to be usable.
+fn is_usable_dependency(dep: &NodeDep, dev_dependencies: bool) -> bool {
+    dep.dep_kinds.is_empty()
+        || dep.dep_kinds.iter().any(|info| match info.kind {
+            DependencyKind::Normal => true,
+            DependencyKind::Development => dev_dependencies,
+            _ => false,
+        })
+}
+
 impl Iterator for LockedDeps {
     type Item = (String, String);
 
     fn next(&mut self) -> Option<(String, String)> {
         let dep = self.dependencies.pop()?;
-        let mut parts = dep.split_whitespace();
-        let name = parts.next()?;
-        let val = parts.next()?;
-        Some((name.replace('-', "_"), val.to_owned()))
+        let (name, val) = parse_package_id(&dep)?;
+        Some((name.replace('-', "_"), val))
+    }
+}
+
+/// Gets the name and version out of a package ID. Older versions of cargo
+/// use `<name> <version> (<source>)`, and newer ones use a URL like
+/// `registry+https://...#<name>@<version>`, `path+file:///...#<name>@<version>`
+/// or `git+https://...?rev=...#<name>@<version>`. In the URL form, the name
+/// is left out if it's the same as the last part of the URL's path.
+fn parse_package_id(id: &str) -> Option<(String, String)> {
+    let (url, fragment) = match id.rsplit_once('#') {
+        Some(parts) => parts,
+        None => {
+            let mut parts = id.split_whitespace();
+            let name = parts.next()?;
+            let val = parts.next()?;
+            return Some((name.to_owned(), val.to_owned()));
+        }
+    };
+
+    match fragment.split_once('@') {
+        Some((name, val)) => Some((name.to_owned(), val.to_owned())),
+        None => {
+            let path = url.split('?').next()?.trim_end_matches('/');
+            let name = path.rsplit('/').next()?.trim_end_matches(".git");
+            Some((name.to_owned(), fragment.to_owned()))
+        }
     }
 }
 
@@ -260,6 +1258,28 @@ struct Fingerprint {
     mtime: SystemTime,
 }
 
+/// Finds the version of the library `libname` in the dep-info (`.d`) file
+/// cargo wrote for it, from the directory its sources were unpacked into,
+/// like `.../registry/src/<index>/serde-1.0.193/src/lib.rs`. Path and git
+/// dependencies aren't in a directory named after their version.
+pub fn version_from_dep_info(dep_info: &str, libname: &str) -> Option<String> {
+    // The first line is `<output>: <sources...>`.
+    let (_, sources) = dep_info.lines().next()?.split_once(": ")?;
+    sources
+        .split(|c: char| c == '/' || c == '\\' || c.is_whitespace())
+        .find_map(|component| {
+            component
+                .match_indices('-')
+                .map(|(i, _)| (&component[..i], &component[i + 1..]))
+                .find(|(name, version)| {
+                    name.replace('-', "_") == libname
+                        && version.starts_with(|c: char| c.is_ascii_digit())
+                        && version.contains('.')
+                })
+                .map(|(_, version)| version.to_string())
+        })
+}
+
 fn guess_ext(mut path: PathBuf, exts: &[&str]) -> Result<PathBuf> {
     for ext in exts {
         path.set_extension(ext);
@@ -290,18 +1310,36 @@ impl Fingerprint {
             .and_then(|e| if e == "json" { Some(e) } else { None })
             .ok_or(ErrorKind::Fingerprint)?;
 
+        // Fingerprints for libraries are called `lib-<name>.json`; the
+        // others are for binaries, build scripts, and so on.
+        let is_lib = matches!(
+            path.file_stem().and_then(OsStr::to_str),
+            Some(stem) if stem.starts_with("lib-")
+        );
+
         let mut rlib = PathBuf::from(path);
         rlib.pop();
         rlib.pop();
         rlib.pop();
         let mut dll = rlib.clone();
+        let dep_info = rlib.join(format!("deps/{}-{}.d", libname, hash));
         rlib.push(format!("deps/lib{}-{}", libname, hash));
         dll.push(format!("deps/{}-{}", libname, hash));
-        rlib = guess_ext(rlib, &["rlib", "so", "dylib"]).or_else(|_| guess_ext(dll, &["dll"]))?;
+        rlib = match guess_ext(rlib, &["rlib", "so", "dylib", "a"])
+            .or_else(|_| guess_ext(dll, &["dll", "lib"]))
+        {
+            Ok(rlib) => rlib,
+            Err(_) if is_lib => return Err(ErrorKind::MissingArtifact(libname).into()),
+            Err(e) => return Err(e),
+        };
+
+        let version = fs::read_to_string(dep_info)
+            .ok()
+            .and_then(|dep_info| version_from_dep_info(&dep_info, &libname));
 
         Ok(Fingerprint {
             libname,
-            version: None,
+            version,
             rlib,
             mtime: fs::metadata(path)?.modified()?,
         })
@@ -317,17 +1355,86 @@ impl Fingerprint {
 }
 
 error_chain! {
-    errors { Fingerprint }
+    errors {
+        Fingerprint
+        MissingArtifact(libname: String) {
+            description("no library artifact for a dependency")
+            display("no library artifact for `{}`", libname)
+        }
+        NoPackages(manifest_dir: String) {
+            description("the manifest has no packages")
+            display("the manifest in `{}` has no packages", manifest_dir)
+        }
+    }
     foreign_links {
         Io(std::io::Error);
         Metadata(cargo_metadata::Error);
+        Toml(toml::de::Error);
+        Json(serde_json::Error);
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompileType {
     Full,
     Check,
+    /// Compile with the built-in test harness, and run each `#[test]`.
+    Test,
+    /// Compile and link a binary, but don't run it.
+    Link,
+}
+
+/// How to pick the edition examples are compiled with, when there are
+/// several packages (with different editions) in the workspace. The
+/// editions of dependencies from outside the workspace don't matter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EditionStrategy {
+    /// The newest edition used by any package in the workspace.
+    #[default]
+    Max,
+    /// The oldest edition used by any package in the workspace.
+    Min,
+    /// The edition of the package in `manifest_dir`.
+    BookCrate,
+}
+
+/// The edition used when none of the packages in `manifest_dir` has an
+/// edition keeper knows.
+pub const DEFAULT_EDITION: &str = "2021";
+
+/// The edition examples get from the project in `manifest_dir`, or `None`
+/// if none of its packages has an edition keeper knows. It's an error
+/// for the project to have no packages at all.
+pub fn manifest_edition(
+    manifest_dir: &Path,
+    strategy: EditionStrategy,
+) -> Result<Option<&'static str>> {
+    let metadata = get_cargo_meta(manifest_dir.join("Cargo.toml"))?;
+    if metadata.packages.is_empty() {
+        return Err(ErrorKind::NoPackages(manifest_dir.display().to_string()).into());
+    }
+    Ok(find_edition(&metadata, strategy))
+}
+
+fn find_edition(
+    metadata: &cargo_metadata::Metadata,
+    strategy: EditionStrategy,
+) -> Option<&'static str> {
+    let editions = metadata
+        .workspace_packages()
+        .into_iter()
+        .filter_map(|package| edition_str(&package.edition));
+
+    match strategy {
+        EditionStrategy::Max => editions.max(),
+        EditionStrategy::Min => editions.min(),
+        EditionStrategy::BookCrate => match metadata.root_package() {
+            Some(package) => edition_str(&package.edition),
+            // A virtual manifest has no package of its own.
+            None => editions.max(),
+        },
+    }
 }
 
 fn edition_str(edition: &Edition) -> Option<&'static str> {
@@ -1,16 +1,23 @@
+use std::collections::HashMap;
 use std::mem;
 
 use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Parser, Tag};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use slug::slugify;
+
+use crate::CrateType;
 
 #[derive(Debug)]
 pub enum Buffer {
     None,
     Code(Vec<String>),
+    /// The contents of a `keeper-output` block.
+    Output(String),
     Heading(String),
 }
 
-fn get_hash(contents: &str) -> String {
+pub fn get_hash(contents: &str) -> String {
     let mut hasher = Sha256::new();
 
     hasher.update(contents.as_bytes());
@@ -18,30 +25,65 @@ fn get_hash(contents: &str) -> String {
     base64_url::encode(hasher.finalize().as_slice())
 }
 
-pub fn extract_tests_from_string(s: &str, file_stem: &str) -> (Vec<Test>, Option<String>) {
-    let mut tests = Vec::new();
+/// The templates defined by `skeptic-template` code blocks in a file.
+#[derive(Debug, Default)]
+pub struct Templates {
+    /// The template from a block which wasn't given a name.
+    pub old_template: Option<String>,
+    /// Templates from blocks tagged `skt-<name>`, keyed by name.
+    pub named: HashMap<String, String>,
+}
+
+/// Finds the tests in the markdown `s`. If `accumulate` is set, each
+/// test gets the definitions from the tests before it in `s` as a prelude.
+/// If `rustdoc_compat` is set, blocks with no language are Rust blocks.
+/// A block tagged `continued` is added on to the previous Rust block, as
+/// long as there's no heading or template between them. A block tagged
+/// `keeper-replace` replaces the code of the block a `continued` block
+/// would continue, and one tagged `keeper-replace-<id>` replaces the code
+/// of an earlier block tagged `keeper-id-<id>`.
+pub fn extract_tests_from_string(
+    s: &str,
+    file_stem: &str,
+    accumulate: bool,
+    rustdoc_compat: bool,
+) -> (Vec<Test>, Templates) {
+    let mut tests: Vec<Test> = Vec::new();
+    let mut accumulated = Vec::new();
     let mut buffer = Buffer::None;
     let parser = Parser::new(s);
     let mut section = None;
     let mut code_block_start = 0;
+    // Whether a `continued` block would continue the last test: only
+    // if nothing but prose and non-Rust blocks have come since.
+    let mut can_continue = false;
+    // The tests tagged `keeper-id-<id>`, so they can be replaced.
+    let mut ids = HashMap::new();
     // Oh this isn't actually a test but a legacy template
-    let mut old_template = None;
+    let mut templates = Templates::default();
 
     for (event, range) in parser.into_offset_iter() {
         let line_number = bytecount::count(&s.as_bytes()[0..range.start], b'\n');
         match event {
             Event::Start(Tag::Heading(level, ..)) if level < HeadingLevel::H3 => {
                 buffer = Buffer::Heading(String::new());
+                can_continue = false;
             }
             Event::End(Tag::Heading(level, ..)) if level < HeadingLevel::H3 => {
                 let cur_buffer = mem::replace(&mut buffer, Buffer::None);
                 if let Buffer::Heading(sect) = cur_buffer {
-                    section = Some(sanitize_test_name(&sect));
+                    // Headings of only punctuation (or emoji) name nothing.
+                    section = Some(sanitize_test_name(&sect)).filter(|sect| !sect.is_empty());
                 }
             }
             Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
                 let code_block_info = parse_code_block_info(info);
-                if code_block_info.is_rust {
+                if code_block_info.is_output {
+                    buffer = Buffer::Output(String::new());
+                // Like rustdoc, treat blocks without any other language as Rust.
+                } else if code_block_info.is_rust
+                    || (rustdoc_compat && !code_block_info.has_other_tags)
+                {
                     buffer = Buffer::Code(Vec::new());
                 }
             }
@@ -51,58 +93,178 @@ pub fn extract_tests_from_string(s: &str, file_stem: &str) -> (Vec<Test>, Option
                         code_block_start = line_number;
                     }
                     buf.extend(text.lines().map(|s| format!("{}\n", s)));
+                } else if let Buffer::Output(ref mut buf) = buffer {
+                    buf.push_str(&text);
                 } else if let Buffer::Heading(ref mut buf) = buffer {
                     buf.push_str(&text);
                 }
             }
             Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
-                let code_block_info = parse_code_block_info(info);
-                if let Buffer::Code(buf) = mem::replace(&mut buffer, Buffer::None) {
+                let mut code_block_info = parse_code_block_info(info);
+                let cur_buffer = mem::replace(&mut buffer, Buffer::None);
+                if let Buffer::Output(output) = cur_buffer {
+                    // The expected output of the test just before it, if
+                    // there's been nothing but prose in between.
+                    if can_continue {
+                        let test = tests.last_mut().expect("there is a test to check");
+                        test.expected_output = Some(output);
+                        test.rehash();
+                    }
+                } else if let Buffer::Code(mut buf) = cur_buffer {
+                    let directives = take_directives(&mut buf, &mut code_block_info);
+                    let replaced = match &code_block_info.replaces_id {
+                        Some(id) => ids.get(id).copied(),
+                        None if code_block_info.replaces_previous && can_continue => {
+                            Some(tests.len() - 1)
+                        }
+                        None => None,
+                    };
                     if code_block_info.is_old_template {
-                        old_template = Some(buf.into_iter().collect())
+                        can_continue = false;
+                        let template = buf.into_iter().collect();
+                        match code_block_info.template {
+                            Some(name) => {
+                                templates.named.insert(name, template);
+                            }
+                            None => templates.old_template = Some(template),
+                        }
+                    } else if let Some(index) = replaced {
+                        // Test this block's code instead of the (visible) one it replaces.
+                        let test = &mut tests[index];
+                        test.text = buf;
+                        test.rehash();
+                    } else if code_block_info.continued && can_continue {
+                        // Compile this block as part of the one before it.
+                        let test = tests.last_mut().expect("there is a test to continue");
+                        test.text.extend(buf);
+                        test.rehash();
                     } else {
                         let name = if let Some(ref section) = section {
                             format!("{}_sect_{}_line_{}", file_stem, section, code_block_start)
                         } else {
                             format!("{}_line_{}", file_stem, code_block_start)
                         };
-                        tests.push(Test {
+                        // The previous test can't be continued any more,
+                        // so its definitions are complete.
+                        if let Some(last) = tests
+                            .last()
+                            .filter(|last| accumulate && !last.ignore && !last.compile_fail)
+                        {
+                            accumulate_definitions(&mut accumulated, remove_main(&last.text));
+                        }
+                        let prelude = prelude_for(&accumulated, &buf);
+                        let no_std = is_no_std(&buf);
+                        let mut test = Test {
                             name,
+                            file: file_stem.to_string(),
+                            file_position: 0,
+                            line: code_block_start + 1,
                             ignore: code_block_info.ignore,
+                            ignore_reason: code_block_info.ignore_reason,
                             compile_fail: code_block_info.compile_fail,
-                            no_run: code_block_info.no_run,
+                            no_std,
+                            // no_std examples can't be run like other examples.
+                            no_run: code_block_info.no_run || no_std,
                             should_panic: code_block_info.should_panic,
                             template: code_block_info.template,
-                            hash: get_hash(&buf.join("\n")),
+                            exit_code: code_block_info.exit_code,
+                            edition: code_block_info.edition,
+                            editions: code_block_info.editions,
+                            ignore_platforms: code_block_info.ignore_platforms,
+                            only_platforms: code_block_info.only_platforms,
+                            max_compile_ms: code_block_info.max_compile_ms,
+                            crate_type: code_block_info.crate_type,
+                            expected_output: None,
+                            directives,
+                            hash: String::new(),
+                            prelude,
                             text: buf,
-                        });
+                        };
+                        test.rehash();
+                        tests.push(test);
+                        if let Some(id) = code_block_info.id {
+                            ids.insert(id, tests.len() - 1);
+                        }
+                        can_continue = true;
                     }
                 }
             }
             _ => (),
         }
     }
-    (tests, old_template)
+    (tests, templates)
+}
+
+/// Puts the text `annotation` gives after each code block in the markdown
+/// in `s`. It's given the line the block's code starts on (like
+/// [`Test::line`]), and gives `None` for blocks it has nothing to add to.
+/// Blocks which will be hidden aren't annotated.
+pub fn annotate_blocks(s: &str, annotation: impl Fn(usize) -> Option<String>) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut last_end = 0;
+    // Whether we're in a block which could be annotated, and if so,
+    // the line its code starts on (once that's been seen).
+    let mut block: Option<Option<usize>> = None;
+
+    for (event, range) in Parser::new(s).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info)))
+                if !parse_code_block_info(info).is_hidden() =>
+            {
+                block = Some(None);
+            }
+            Event::Text(_) => {
+                if let Some(start @ None) = &mut block {
+                    *start = Some(bytecount::count(&s.as_bytes()[0..range.start], b'\n') + 1);
+                }
+            }
+            Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(_))) => {
+                if let Some(text) = block.take().flatten().and_then(&annotation) {
+                    output.push_str(&s[last_end..range.end]);
+                    output.push_str(&format!("\n\n{}\n\n", text));
+                    last_end = range.end;
+                }
+            }
+            _ => (),
+        }
+    }
+    output.push_str(&s[last_end..]);
+
+    output
+}
+
+/// Removes every Rust code block tagged `hide-all` (or `keeper-replace`)
+/// from the markdown in `s`. These blocks are still tested, but are
+/// scaffolding that readers of the book shouldn't see.
+pub fn remove_hidden_blocks(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut last_end = 0;
+
+    for (event, range) in Parser::new(s).into_offset_iter() {
+        if let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) = event {
+            let code_block_info = parse_code_block_info(info);
+            if code_block_info.is_rust && code_block_info.is_hidden() {
+                output.push_str(&s[last_end..range.start]);
+                last_end = range.end;
+            }
+        }
+    }
+    output.push_str(&s[last_end..]);
+
+    output
 }
 
+/// Turns `s` into lowercase ASCII letters and digits, separated by single
+/// underscores, for use in test and file names. Other scripts are
+/// transliterated (so `日本語` becomes `ri_ben_yu`), so headings which
+/// aren't in English still name their tests, and stay distinct.
 pub fn sanitize_test_name(s: &str) -> String {
-    s.to_ascii_lowercase()
-        .chars()
-        .map(|ch| {
-            if ch.is_ascii() && ch.is_alphanumeric() {
-                ch
-            } else {
-                '_'
-            }
-        })
-        .collect::<String>()
-        .split('_')
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<_>>()
-        .join("_")
+    slugify(s).replace('-', "_")
 }
 
 pub fn parse_code_block_info(info: &str) -> CodeBlockInfo {
+    let (info, ignore_reason) = split_ignore_reason(info);
+
     // Same as rustdoc
     let tokens = info.split(|c: char| !(c == '_' || c == '-' || c.is_alphanumeric()));
 
@@ -110,12 +272,27 @@ pub fn parse_code_block_info(info: &str) -> CodeBlockInfo {
     let mut seen_other_tags = false;
     let mut info = CodeBlockInfo {
         is_rust: false,
+        has_other_tags: false,
         should_panic: false,
         compile_fail: false,
         ignore: false,
+        ignore_reason,
         no_run: false,
+        hide_all: false,
         is_old_template: false,
+        continued: false,
+        id: None,
+        replaces_previous: false,
+        replaces_id: None,
         template: None,
+        exit_code: None,
+        edition: None,
+        editions: Vec::new(),
+        ignore_platforms: Vec::new(),
+        only_platforms: Vec::new(),
+        max_compile_ms: None,
+        crate_type: None,
+        is_output: false,
     };
 
     for token in tokens {
@@ -141,10 +318,49 @@ pub fn parse_code_block_info(info: &str) -> CodeBlockInfo {
                 info.no_run = true;
                 seen_rust_tags = true;
             }
+            "hide-all" => {
+                info.hide_all = true;
+                seen_rust_tags = true;
+            }
+            "continued" => {
+                info.continued = true;
+                seen_rust_tags = true;
+            }
+            "keeper-output" => info.is_output = true,
+            "keeper-replace" => {
+                info.replaces_previous = true;
+                seen_rust_tags = true;
+            }
+            _ if token.starts_with("ignore-") && token.len() > "ignore-".len() => {
+                info.ignore_platforms
+                    .push(token["ignore-".len()..].to_string());
+                seen_rust_tags = true;
+            }
+            _ if token.ends_with("-only") && token.len() > "-only".len() => {
+                info.only_platforms
+                    .push(token[..token.len() - "-only".len()].to_string());
+                seen_rust_tags = true;
+            }
+            _ if token.starts_with("keeper-replace-") => {
+                info.replaces_id = Some(token["keeper-replace-".len()..].to_string());
+                seen_rust_tags = true;
+            }
+            _ if token.starts_with("keeper-id-") => {
+                info.id = Some(token["keeper-id-".len()..].to_string());
+                seen_rust_tags = true;
+            }
+            _ if token.starts_with("edition") && token[7..].parse::<u32>().is_ok() => {
+                info.edition = Some(token[7..].to_string());
+                seen_rust_tags = true;
+            }
             "skeptic-template" => {
                 info.is_old_template = true;
                 seen_rust_tags = true
             }
+            _ if token.starts_with("exit-") && token[5..].parse::<i32>().is_ok() => {
+                info.exit_code = token[5..].parse().ok();
+                seen_rust_tags = true;
+            }
             _ if token.starts_with("skt-") => {
                 info.template = Some(token[4..].to_string());
                 seen_rust_tags = true;
@@ -154,33 +370,238 @@ pub fn parse_code_block_info(info: &str) -> CodeBlockInfo {
     }
 
     info.is_rust &= !seen_other_tags || seen_rust_tags;
+    info.has_other_tags = seen_other_tags;
 
     info
 }
 
+/// Takes the reason out of an info string like `rust,ignore (requires-network)`,
+/// so the words of the reason aren't mistaken for tags.
+fn split_ignore_reason(info: &str) -> (String, Option<String>) {
+    let is_tag_char = |c: char| c == '_' || c == '-' || c.is_alphanumeric();
+    if let Some(start) = info.find("ignore") {
+        let rest = info[start + "ignore".len()..].trim_start();
+        if let Some((reason, after)) = rest.strip_prefix('(').and_then(|r| r.split_once(')')) {
+            if !info[..start].ends_with(is_tag_char) {
+                let remaining = format!("{}ignore{}", &info[..start], after);
+                return (remaining, Some(reason.trim().to_string()));
+            }
+        }
+    }
+    (info.to_string(), None)
+}
+
+/// Removes the `//@ key[: value]` directive lines from the start of a
+/// code block, applying them to `info` as if they were in its info string.
+/// The supported directives are `compile_fail`, `should_panic`,
+/// `ignore[: <reason>]`, `no_run`, `exit: <code>`, `edition: <year>`,
+/// `editions: <years>`, `max_compile_ms: <ms>` and `crate_type: <lib|bin>`.
+/// Returns the directive lines which were removed.
+fn take_directives(buf: &mut Vec<String>, info: &mut CodeBlockInfo) -> Vec<String> {
+    let mut directives = 0;
+    for line in buf.iter() {
+        let directive = match line.trim().strip_prefix("//@") {
+            Some(directive) => directive.trim(),
+            None => break,
+        };
+        let (key, value) = match directive.split_once(':') {
+            Some((key, value)) => (key.trim(), Some(value.trim())),
+            None => (directive, None),
+        };
+        match (key, value) {
+            ("compile_fail", None) => info.compile_fail = true,
+            ("should_panic", None) => info.should_panic = true,
+            ("ignore", None) => info.ignore = true,
+            ("ignore", Some(reason)) if !reason.is_empty() => {
+                info.ignore = true;
+                info.ignore_reason = Some(reason.to_string());
+            }
+            ("no_run", None) => info.no_run = true,
+            ("exit", Some(code)) if code.parse::<i32>().is_ok() => {
+                info.exit_code = code.parse().ok()
+            }
+            ("edition", Some(edition)) if !edition.is_empty() => {
+                info.edition = Some(edition.to_string())
+            }
+            ("editions", Some(editions)) if !editions.is_empty() => {
+                info.editions = editions
+                    .split(|c: char| c == ',' || c == '+' || c.is_whitespace())
+                    .filter(|edition| !edition.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            ("max_compile_ms", Some(ms)) if ms.parse::<u64>().is_ok() => {
+                info.max_compile_ms = ms.parse().ok()
+            }
+            ("crate_type", Some("lib")) => info.crate_type = Some(CrateType::Lib),
+            ("crate_type", Some("bin")) => info.crate_type = Some(CrateType::Bin),
+            // Anything else is left as an ordinary comment.
+            _ => break,
+        }
+        directives += 1;
+    }
+    buf.drain(..directives).collect()
+}
+
 #[derive(Debug)]
 pub struct CodeBlockInfo {
     is_rust: bool,
+    /// Whether the info string has tags which keeper doesn't recognise,
+    /// like the name of another language.
+    has_other_tags: bool,
     should_panic: bool,
     ignore: bool,
+    /// The `<reason>` from `ignore (<reason>)`, or an
+    /// `//@ ignore: <reason>` directive.
+    ignore_reason: Option<String>,
     compile_fail: bool,
     no_run: bool,
+    hide_all: bool,
     is_old_template: bool,
+    continued: bool,
+    /// The `<id>` from a `keeper-id-<id>` tag.
+    id: Option<String>,
+    replaces_previous: bool,
+    /// The `<id>` from a `keeper-replace-<id>` tag.
+    replaces_id: Option<String>,
     template: Option<String>,
+    exit_code: Option<i32>,
+    /// The edition from an `edition<year>` tag, or an
+    /// `//@ edition: <year>` directive.
+    edition: Option<String>,
+    /// The editions from an `//@ editions: <years>` directive.
+    editions: Vec<String>,
+    /// The `<platform>`s from `ignore-<platform>` tags.
+    ignore_platforms: Vec<String>,
+    /// The `<platform>`s from `<platform>-only` tags.
+    only_platforms: Vec<String>,
+    /// The budget from an `//@ max_compile_ms: <ms>` directive.
+    max_compile_ms: Option<u64>,
+    /// The crate type from an `//@ crate_type: <lib|bin>` directive.
+    crate_type: Option<CrateType>,
+    /// Whether this is a `keeper-output` block, holding what the
+    /// test before it should print.
+    is_output: bool,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+impl CodeBlockInfo {
+    /// Whether this block is left out of the rendered book.
+    fn is_hidden(&self) -> bool {
+        self.hide_all || self.replaces_previous || self.replaces_id.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Test {
     pub(crate) name: String,
+    /// The file (usually the chapter) this test came from.
+    pub(crate) file: String,
+    /// Where `file` comes in the book, so results can be reported in the
+    /// book's order (`chapter_10` is after `chapter_2`).
+    #[serde(default)]
+    pub(crate) file_position: usize,
+    /// The line in `file` the code of this test starts on.
+    pub(crate) line: usize,
     pub(crate) text: Vec<String>,
+    /// Code carried forward from earlier tests in the same file, which
+    /// goes before `text` when the test is compiled.
+    pub(crate) prelude: Vec<String>,
     pub(crate) ignore: bool,
+    /// Why the block is ignored, from `ignore (<reason>)`.
+    pub(crate) ignore_reason: Option<String>,
     pub(crate) compile_fail: bool,
     pub(crate) no_run: bool,
+    /// Whether this is a `#![no_std]` crate (or `no_std` is set), which
+    /// can only be type-checked. Such tests are `no_run` too.
+    #[serde(default)]
+    pub(crate) no_std: bool,
     pub(crate) should_panic: bool,
     pub(crate) template: Option<String>,
+    pub(crate) exit_code: Option<i32>,
+    /// The edition to compile this test with, instead of the configured one.
+    pub(crate) edition: Option<String>,
+    /// The editions to compile this test with, each as a separate test,
+    /// from an `//@ editions: <years>` directive.
+    pub(crate) editions: Vec<String>,
+    /// The platforms this test isn't for, from `ignore-<platform>` tags.
+    pub(crate) ignore_platforms: Vec<String>,
+    /// The only platforms this test is for, from `<platform>-only` tags.
+    pub(crate) only_platforms: Vec<String>,
+    /// How long this test may take to compile, in milliseconds,
+    /// instead of the configured `max_compile_ms`.
+    pub(crate) max_compile_ms: Option<u64>,
+    /// The kind of crate to compile this test as, instead of the
+    /// configured one, from an `//@ crate_type: <lib|bin>` directive.
+    pub(crate) crate_type: Option<CrateType>,
+    /// What the test must print to stdout, from a `keeper-output` block.
+    pub(crate) expected_output: Option<String>,
+    /// The `//@` directive lines taken from the start of the block, kept
+    /// so that changing one changes the test's hash.
+    pub(crate) directives: Vec<String>,
     pub(crate) hash: String,
 }
 
+impl Test {
+    /// The test's name, like `chapter_1.md_line_9`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file (usually the chapter) this test came from.
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// The line in [`Test::file`] the code of this test starts on.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The block's code as readers see it, without its hidden (`# `) lines.
+    pub fn visible_code(&self) -> String {
+        self.text
+            .iter()
+            .filter(|line| clean_omitted_line(line) == line.as_str())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Recomputes this test's hash after its code (or expected output)
+    /// has changed.
+    fn rehash(&mut self) {
+        self.hash = self.code_hash();
+    }
+
+    /// A hash of this test's code (and directives, and expected output),
+    /// without any fingerprints mixed in later.
+    pub(crate) fn code_hash(&self) -> String {
+        let mut hash = get_hash(&[&self.prelude[..], &self.text[..]].concat().join("\n"));
+        if !self.directives.is_empty() {
+            hash = get_hash(&format!("{}{}", hash, self.directives.concat()));
+        }
+        match &self.expected_output {
+            Some(output) => get_hash(&format!("{}{}", hash, output)),
+            None => hash,
+        }
+    }
+
+    /// Mixes `fingerprint` into this test's hash, so the test isn't
+    /// considered cached if the fingerprint changes.
+    pub(crate) fn add_fingerprint(&mut self, fingerprint: &str) {
+        self.hash = get_hash(&format!("{}{}", self.hash, fingerprint));
+    }
+
+    /// The exit code this test is expected to finish with, if
+    /// it was given one (with `exit-<code>`) and it will be run.
+    pub(crate) fn expected_exit_code(&self) -> Option<i32> {
+        if self.no_run {
+            None
+        } else {
+            self.exit_code
+        }
+    }
+}
+
 /// Just like Rustdoc, ignore a "#" sign at the beginning of a line of code.
 /// These are commonly an indication to omit the line from user-facing
 /// documentation but include it for the purpose of playground links or skeptic
@@ -205,8 +626,419 @@ fn clean_omitted_line(line: &str) -> &str {
     }
 }
 
-/// Creates the Rust code that this test will be operating on.
-pub fn create_test_input(lines: &[String]) -> String {
+/// If the brackets in the code of `lines` (once hidden lines are
+/// revealed) don't match up, returns a hint about the line at fault.
+/// A mismatch here usually means a line meant to be hidden was
+/// mistyped (like `#fn main() {`), or a hidden line was left out.
+pub fn hidden_line_hint(lines: &[String]) -> Option<String> {
+    let code = strip_comments_and_literals(&clean_lines(lines));
+    let mut open: Vec<(char, usize)> = Vec::new();
+    let mut unmatched = None;
+    'lines: for (number, line) in code.lines().enumerate() {
+        for ch in line.chars() {
+            let expected = match ch {
+                '(' | '[' | '{' => {
+                    open.push((ch, number));
+                    continue;
+                }
+                ')' => '(',
+                ']' => '[',
+                '}' => '{',
+                _ => continue,
+            };
+            match open.pop() {
+                Some((opening, _)) if opening == expected => {}
+                _ => {
+                    unmatched = Some((ch, number));
+                    break 'lines;
+                }
+            }
+        }
+    }
+    let (bracket, number) = unmatched.or_else(|| open.pop())?;
+
+    let mut hint = format!(
+        "the `{}` on line {} of the code block is unmatched",
+        bracket,
+        number + 1
+    );
+    // A `#` directly followed by code was probably meant to hide the line.
+    let mistyped = lines.iter().find(|line| {
+        let trimmed = line.trim();
+        matches!(
+            trimmed.strip_prefix('#').and_then(|rest| rest.chars().next()),
+            Some(c) if !matches!(c, ' ' | '\t' | '#' | '[' | '!')
+        )
+    });
+    if let Some(line) = mistyped {
+        hint.push_str(&format!(
+            "; `{}` looks like a hidden line missing the space after `#`",
+            line.trim()
+        ));
+    } else if lines
+        .iter()
+        .any(|line| clean_omitted_line(line) != line.as_str())
+    {
+        hint.push_str("; check that no hidden (`# `) lines are missing");
+    }
+
+    Some(hint)
+}
+
+/// Returns `lines` without its `fn main`, leaving just the definitions
+/// that later tests might build on.
+fn remove_main(lines: &[String]) -> Vec<String> {
+    let mut output = Vec::new();
+    let mut depth = 0;
+    let mut in_main = false;
+
+    for line in lines {
+        let code = strip_comments_and_literals(clean_omitted_line(line));
+        if !in_main && code.trim_start().starts_with("fn main(") {
+            in_main = true;
+            depth = 0;
+        }
+        if in_main {
+            for ch in code.chars() {
+                match ch {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {}
+                }
+            }
+            if depth <= 0 && code.contains('}') {
+                in_main = false;
+            }
+        } else {
+            output.push(line.clone());
+        }
+    }
+
+    output
+}
+
+/// Adds the definitions in `lines` to the `accumulated` prelude. A `use`
+/// which is already there isn't repeated, and crate attributes (`#![...]`)
+/// go at the top, since they must come before any item.
+fn accumulate_definitions(accumulated: &mut Vec<String>, lines: Vec<String>) {
+    for line in lines {
+        let code = clean_omitted_line(&line).trim().to_string();
+        let already_there = || {
+            accumulated
+                .iter()
+                .any(|line| clean_omitted_line(line).trim() == code)
+        };
+        if code.starts_with("#![") {
+            if !already_there() {
+                let top = accumulated
+                    .iter()
+                    .take_while(|line| clean_omitted_line(line).trim().starts_with("#!["))
+                    .count();
+                accumulated.insert(top, line);
+            }
+        } else if !(is_use_line(&code) && already_there()) {
+            accumulated.push(line);
+        }
+    }
+}
+
+/// The prelude for a block with the code `text`: the `accumulated`
+/// definitions, without any `use` the block makes itself.
+fn prelude_for(accumulated: &[String], text: &[String]) -> Vec<String> {
+    let own_uses = text
+        .iter()
+        .map(|line| clean_omitted_line(line).trim())
+        .filter(|line| is_use_line(line))
+        .collect::<Vec<_>>();
+    accumulated
+        .iter()
+        .filter(|line| !own_uses.contains(&clean_omitted_line(line).trim()))
+        .cloned()
+        .collect()
+}
+
+/// Whether `line` (without indentation) is a whole `use` declaration.
+fn is_use_line(line: &str) -> bool {
+    (line.starts_with("use ") || line.starts_with("pub use ")) && line.ends_with(';')
+}
+
+/// Returns `code` with comments removed, and the contents of string and
+/// character literals blanked out. This lets simple textual checks look
+/// at the "shape" of some code without tripping over prose.
+pub fn strip_comments_and_literals(code: &str) -> String {
+    let chars = code.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(code.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        match (chars[i], chars.get(i + 1)) {
+            ('/', Some('/')) => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            ('/', Some('*')) => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    if chars[i] == '\n' {
+                        out.push('\n');
+                    }
+                    i += 1;
+                }
+                i += 2;
+                continue;
+            }
+            ('"', _) => {
+                out.push('"');
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    } else if chars[i] == '\n' {
+                        out.push('\n');
+                    }
+                    i += 1;
+                }
+                out.push('"');
+            }
+            // Character literals (as opposed to lifetimes).
+            ('\'', Some('\\')) => {
+                i += 3;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += 1;
+                }
+                out.push_str("' '");
+            }
+            ('\'', Some(_)) if chars.get(i + 2) == Some(&'\'') => {
+                out.push_str("' '");
+                i += 2;
+            }
+            (ch, _) => out.push(ch),
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// Decides whether a code block is (probably) a fragment of a larger
+/// program, which was never meant to compile on its own. Ignoring
+/// comments and blank lines, a block is a fragment if:
+///
+///  - it starts with a `.` (i.e. it continues a method chain),
+///  - it contains a `...` placeholder, or
+///  - it is a single line which doesn't end in `;` or `}`.
+pub fn is_fragment(lines: &[String]) -> bool {
+    let code = strip_comments_and_literals(&clean_lines(lines));
+    let code_lines = code
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    match code_lines.as_slice() {
+        [] => false,
+        [first, ..] if first.starts_with('.') => true,
+        _ if code.contains("...") => true,
+        [only] => !(only.ends_with(';') || only.ends_with('}')),
+        _ => false,
+    }
+}
+
+/// Whether the code block refers to any of the crates in `names`.
+pub fn uses_dependencies(lines: &[String], names: &[String]) -> bool {
+    strip_comments_and_literals(&clean_lines(lines))
+        .split(|c: char| !(c == '_' || c.is_alphanumeric()))
+        .any(|token| names.iter().any(|name| name == token))
+}
+
+/// Whether the code block is a `#![no_std]` crate.
+pub fn is_no_std(lines: &[String]) -> bool {
+    strip_comments_and_literals(&clean_lines(lines)).contains("#![no_std]")
+}
+
+/// Whether the code block contains `#[test]` functions, in which case it
+/// should be compiled and run with the built-in test harness.
+pub fn uses_test_harness(lines: &[String]) -> bool {
+    strip_comments_and_literals(&clean_lines(lines)).contains("#[test]")
+}
+
+/// The wrapper template used if none is configured, which
+/// leaves the code as it is.
+pub const DEFAULT_WRAPPER_TEMPLATE: &str = "{code}";
+
+/// Creates the Rust code that this test will be operating on, by
+/// putting the cleaned code in place of `{code}` in `wrapper_template`.
+pub fn create_test_input(lines: &[String], wrapper_template: &str) -> String {
+    wrapper_template.replace("{code}", &clean_lines(lines))
+}
+
+/// How `main` is written for examples without one, with `rustdoc_compat`.
+/// Without one of these, `main` returns a `Result` only if the example
+/// ends in `(())`, as with rustdoc.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum MainWrapper {
+    Kind(MainKind),
+    /// `main` returns `return_type`, and each of `uses` is imported.
+    Custom {
+        return_type: String,
+        #[serde(default)]
+        uses: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MainKind {
+    /// `fn main()`, even if the example ends in `(())`.
+    Unit,
+    /// `fn main() -> Result<(), Box<dyn std::error::Error>>`.
+    Result,
+}
+
+impl MainWrapper {
+    /// The paths imported before `main`.
+    fn uses(&self) -> &[String] {
+        match self {
+            MainWrapper::Custom { uses, .. } => uses,
+            MainWrapper::Kind(_) => &[],
+        }
+    }
+}
+
+/// Which of `lines` each line of the file written for a test came from
+/// (by index), or `None` for lines keeper added (from `wrapper_template`,
+/// or the `main` added with `rustdoc_compat`), so rustc's line numbers
+/// can be traced back to the book. Lines past the end came from the
+/// wrapper too.
+pub fn line_origins(
+    lines: &[String],
+    wrapper_template: &str,
+    rustdoc_compat: bool,
+    main_wrapper: Option<&MainWrapper>,
+) -> Vec<Option<usize>> {
+    let before_code = wrapper_template
+        .find("{code}")
+        .map_or(0, |start| wrapper_template[..start].matches('\n').count());
+    let mut origins = vec![None; before_code];
+
+    let code = lines
+        .iter()
+        .map(|s| clean_rustdoc_line(s))
+        .collect::<String>();
+    if !rustdoc_compat || defines_main(&code) {
+        origins.extend((0..lines.len()).map(Some));
+        return origins;
+    }
+
+    // As written by `create_rustdoc_test_input`: `#![allow(unused)]`, the
+    // crate-level lines, the imports, the line opening `main`, and then
+    // the rest of the lines.
+    let (crate_level, body): (Vec<usize>, Vec<usize>) =
+        (0..lines.len()).partition(|&index| is_crate_level(&clean_rustdoc_line(&lines[index])));
+    let uses = main_wrapper.map_or(0, |wrapper| wrapper.uses().len());
+    origins.push(None);
+    origins.extend(crate_level.into_iter().map(Some));
+    origins.extend(std::iter::repeat_n(None, uses + 1));
+    origins.extend(body.into_iter().map(Some));
+    origins
+}
+
+/// Whether `code` has its own `fn main`, outside comments and strings.
+/// Functions like `fn main_helper` don't count.
+fn defines_main(code: &str) -> bool {
+    let code = strip_comments_and_literals(code);
+    code.match_indices("fn main").any(|(start, found)| {
+        code[start + found.len()..].starts_with(|c: char| c == '(' || c.is_whitespace())
+    })
+}
+
+/// Whether a line must go before the `main` rustdoc adds, rather than in
+/// it: crate attributes, and `extern crate`s.
+fn is_crate_level(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("#![") || line.starts_with("extern crate")
+}
+
+/// Creates the Rust code for a test in the same way as rustdoc: `#` lines
+/// are treated like rustdoc treats them, and if the code doesn't have a
+/// `fn main`, it's put in one. If the code ends in `(())` (like `Ok(())`),
+/// that `main` returns a `Result`, so the code can use `?`. `main_wrapper`
+/// changes how `main` is written.
+pub fn create_rustdoc_test_input(
+    lines: &[String],
+    wrapper_template: &str,
+    main_wrapper: Option<&MainWrapper>,
+) -> String {
+    let code = lines
+        .iter()
+        .map(|s| clean_rustdoc_line(s))
+        .collect::<String>();
+
+    if defines_main(&code) {
+        return wrapper_template.replace("{code}", &code);
+    }
+
+    // Crate attributes and `extern crate`s can't go inside `main`.
+    let (crate_level, body): (Vec<&str>, Vec<&str>) =
+        code.lines().partition(|line| is_crate_level(line));
+    let body = body.join("\n");
+
+    let mut wrapped = String::from("#![allow(unused)]\n");
+    for line in crate_level {
+        wrapped.push_str(line);
+        wrapped.push('\n');
+    }
+    for path in main_wrapper.map_or(&[][..], MainWrapper::uses) {
+        wrapped.push_str(&format!("use {};\n", path));
+    }
+    let returns_result = body.trim_end().ends_with("(())");
+    // A `main` which returns something gets `Ok(())` at the end, unless
+    // the example already ends in it.
+    let tail = if returns_result { "" } else { "Ok(())\n" };
+    match main_wrapper {
+        None if returns_result => wrapped.push_str(&format!(
+            "fn main() {{ fn _inner() -> Result<(), impl core::fmt::Debug> {{\n{}\n}} _inner().unwrap() }}\n",
+            body
+        )),
+        None | Some(MainWrapper::Kind(MainKind::Unit)) => {
+            wrapped.push_str(&format!("fn main() {{\n{}\n}}\n", body))
+        }
+        Some(MainWrapper::Kind(MainKind::Result)) => wrapped.push_str(&format!(
+            "fn main() -> Result<(), Box<dyn std::error::Error>> {{\n{}\n{}}}\n",
+            body, tail
+        )),
+        Some(MainWrapper::Custom { return_type, .. }) => wrapped.push_str(&format!(
+            "fn main() -> {} {{\n{}\n{}}}\n",
+            return_type, body, tail
+        )),
+    }
+
+    wrapper_template.replace("{code}", &wrapped)
+}
+
+/// Like [`clean_omitted_line`], but following rustdoc's rules exactly: a
+/// line starting with `# ` or `#\t` (or just `#`) is hidden, and a line
+/// starting with `##` is shown, starting with a single `#`.
+fn clean_rustdoc_line(line: &str) -> String {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+
+    if trimmed.starts_with("##") {
+        format!("{}{}", indent, &trimmed[1..])
+    } else if trimmed.starts_with("# ") || trimmed.starts_with("#\t") {
+        trimmed[2..].to_string()
+    } else if trimmed.trim_end() == "#" {
+        "\n".to_string()
+    } else {
+        line.to_string()
+    }
+}
+
+/// The code in `lines`, without the `#` that hides lines from readers.
+fn clean_lines(lines: &[String]) -> String {
     lines
         .iter()
         .map(|s| clean_omitted_line(s).to_owned())
@@ -1,13 +1,13 @@
 use anyhow::Error;
 use fs_extra::copy_items;
-use mdbook::book::Book;
+use mdbook::book::{Book, BookItem};
 use mdbook::config::BuildConfig;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use tempfile::{tempdir, TempDir};
 use toml::value::{Table, Value};
 
-use crate::run_tests::TestResult;
+use crate::run_tests::{SkipReason, TestResult};
 use crate::BookKeeper;
 
 fn make_tmpdir_like(path: &Path) -> TempDir {
@@ -26,9 +26,11 @@ fn get_starting_directories(book_name: &str) -> Result<(TempDir, Book), Error> {
     let root_tempdir_obj = make_tmpdir_like(&d);
     let root_tempdir = root_tempdir_obj.path().to_path_buf();
 
-    let mut src_tempdir = root_tempdir.clone();
-    src_tempdir.push(book_name);
-    src_tempdir.push("src");
+    // Books can keep their chapters somewhere other than `src`.
+    let mut book_root = root_tempdir.clone();
+    book_root.push(book_name);
+    let config = mdbook::Config::from_disk(book_root.join("book.toml"))?;
+    let src_tempdir = book_root.join(&config.book.src);
 
     let build_config = BuildConfig {
         // since we never build, this can be anything.
@@ -72,7 +74,7 @@ fn short_book() -> Result<(), Error> {
         .map(|(t, res)| (t.text[0].trim().to_string(), (t, res)))
         .collect::<HashMap<_, _>>();
 
-    assert_eq!(test_list.len(), 6);
+    assert_eq!(test_list.len(), 7);
 
     assert!(test_list.contains_key("// compile-error"));
     assert!(matches!(
@@ -104,6 +106,26 @@ fn short_book() -> Result<(), Error> {
         TestResult::CompileFailed(_)
     ));
 
+    assert!(test_list.contains_key("// exit-code"));
+    let (ref test, ref result) = test_list["// exit-code"];
+    assert!(matches!(result, TestResult::RunFailed(_)));
+    assert!(result.met_test_expectations(test, None));
+
+    Ok(())
+}
+
+#[test]
+fn test_harness_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("test_harness_book")?;
+    let result = BookKeeper::new().real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+
+    let harness_result = result.values().next().unwrap();
+    assert_eq!(harness_result.kind(), crate::Outcome::RunFailed);
+    let harness_output = harness_result.stdout().unwrap();
+    assert!(harness_output.contains("test passes ... ok"));
+    assert!(harness_output.contains("test fails ... FAILED"));
+
     Ok(())
 }
 
@@ -128,7 +150,13 @@ fn long_book() -> Result<(), Error> {
         Value::Array(vec![Value::String("nom".to_string())]),
     );
     let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
-    crate::print_results(&result);
+    let config = crate::KeeperConfig::new(Some(&table), root_tempdir)?;
+    crate::print_results(
+        &mut std::io::stderr(),
+        &result,
+        &config,
+        &crate::Timings::default(),
+    )?;
 
     assert_eq!(result.len(), 5);
 
@@ -188,3 +216,3472 @@ fn nested_book() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn fragment_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("fragment_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(String::from("skip_fragments"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    let test_list = result
+        .into_iter()
+        .map(|(t, res)| (t.text[0].trim().to_string(), (t, res)))
+        .collect::<HashMap<_, _>>();
+
+    assert_eq!(test_list.len(), 4);
+
+    for fragment in ["// method-chain", "// placeholder", "// expression"] {
+        assert!(matches!(
+            test_list[fragment].1,
+            TestResult::Skipped(SkipReason::Fragment)
+        ));
+    }
+
+    assert!(matches!(test_list["// ok"].1, TestResult::Successful(_)));
+
+    Ok(())
+}
+
+#[test]
+fn hidden_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("hidden_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+
+    let table = Table::new();
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .values()
+        .all(|res| matches!(res, TestResult::Successful(_))));
+
+    let BookItem::Chapter(ref chapter) = book.sections[0] else {
+        panic!("Expected a chapter");
+    };
+    assert!(!chapter.content.contains("// hidden"));
+    assert!(chapter.content.contains("// visible"));
+
+    Ok(())
+}
+
+/// The results of the edition book's blocks, in order: one which needs
+/// 2018 or later, then one which needs 2021 or later.
+fn run_edition_book(edition_strategy: &str) -> Result<Vec<TestResult>, Error> {
+    let (tmp_dir, mut book) = get_starting_directories("edition_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("edition_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("edition_strategy"),
+        Value::String(edition_strategy.to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 2);
+
+    let mut result = result.into_iter().collect::<Vec<_>>();
+    result.sort_by_key(|(test, _)| test.line);
+    Ok(result.into_iter().map(|(_, result)| result).collect())
+}
+
+// The workspace has a 2018 and a 2021 package, and depends on a 2015
+// package from outside it, which mustn't make `min` use 2015.
+
+#[test]
+fn edition_strategy_max() -> Result<(), Error> {
+    let result = run_edition_book("max")?;
+    assert!(matches!(result[0], TestResult::Successful(_)));
+    assert!(matches!(result[1], TestResult::Successful(_)));
+
+    Ok(())
+}
+
+#[test]
+fn edition_strategy_min() -> Result<(), Error> {
+    let result = run_edition_book("min")?;
+    assert!(matches!(result[0], TestResult::Successful(_)));
+    assert!(matches!(result[1], TestResult::CompileFailed(_)));
+
+    Ok(())
+}
+
+#[test]
+fn edition_strategy_book_crate() -> Result<(), Error> {
+    let result = run_edition_book("book-crate")?;
+    assert!(matches!(result[0], TestResult::Successful(_)));
+    assert!(matches!(result[1], TestResult::CompileFailed(_)));
+
+    Ok(())
+}
+
+#[test]
+fn empty_book_skips_build() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("empty_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+
+    // Building this fails, but with nothing to test, that doesn't matter.
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(root_tempdir.join("does_not_exist").display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn template_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("template_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+
+    let table = Table::new();
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book);
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("chapter_1.md_sect_chapter_1_line_"));
+    assert!(error.contains("`missing`"));
+
+    // An ignored block is never compiled, so its template isn't needed.
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut chapter) = item {
+            chapter.content = chapter
+                .content
+                .replace("rust,skt-missing", "rust,ignore,skt-missing");
+        }
+    });
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    // Ignored blocks have no result at all.
+    assert!(result.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn cache_without_index_is_invalidated() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let table = Table::new();
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    // Pretend the cache was written by a version of keeper without an index.
+    let mut index = root_tempdir.join("doctest_cache");
+    index.push(crate::cache::INDEX_FILE);
+    std::fs::remove_file(index)?;
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn chapter_cache_survives_an_old_index() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cache_dir = root_tempdir.join("doctest_cache");
+
+    let bookkeeper = BookKeeper::new();
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+
+    // Pretend the cache was written by another version of keeper.
+    std::fs::write(
+        cache_dir.join(crate::cache::INDEX_FILE),
+        r#"{"version": "0.0.0", "hash_algorithm": "sha256-base64url"}"#,
+    )?;
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // What this run saved is still there for the next one, and the
+    // cache is still this book's.
+    assert!(cache_dir.join(crate::cache::CHAPTERS_FILE).is_file());
+    assert!(crate::cache::read_owner(&cache_dir).is_some());
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    Ok(())
+}
+
+#[test]
+fn long_book_with_target() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("long_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("long_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    // Building with an explicit target puts the dependencies in
+    // `target/<triple>/debug/deps`, rather than `target/debug/deps`.
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("externs"),
+        Value::Array(vec![Value::String("nom".to_string())]),
+    );
+    table.insert(
+        String::from("target"),
+        Value::String(current_platform::CURRENT_PLATFORM.to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    let mut target_deps = root_tempdir.join("doctest_cache");
+    target_deps.push("target");
+    target_deps.push(current_platform::CURRENT_PLATFORM);
+    target_deps.push("debug");
+    target_deps.push("deps");
+    assert!(target_deps.is_dir());
+
+    assert_eq!(result.len(), 5);
+    assert!(result
+        .values()
+        .all(|res| matches!(res, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn failing_project_build_is_reported() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let cargo_dir = root_tempdir.join("broken_project");
+    std::fs::create_dir_all(cargo_dir.join("src"))?;
+    std::fs::write(
+        cargo_dir.join("Cargo.toml"),
+        "[package]\nname = \"broken\"\nversion = \"0.1.0\"\n",
+    )?;
+    std::fs::write(
+        cargo_dir.join("src").join("lib.rs"),
+        "fn broken() -> u8 { \"\" }",
+    )?;
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book);
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("`cargo build` failed"));
+    assert!(error.contains("mismatched types"));
+
+    Ok(())
+}
+
+#[test]
+fn profiles_override_base_config() -> Result<(), Error> {
+    let mut ci = Table::new();
+    ci.insert(String::from("skip_fragments"), Value::Boolean(true));
+    ci.insert(String::from("incremental"), Value::Boolean(false));
+    let mut profiles = Table::new();
+    profiles.insert(String::from("ci"), Value::Table(ci));
+
+    let mut table = Table::new();
+    table.insert(String::from("incremental"), Value::Boolean(true));
+    table.insert(String::from("terminal_colors"), Value::Boolean(false));
+    table.insert(String::from("profiles"), Value::Table(profiles));
+
+    let base = crate::apply_profile(&table, None)?;
+    assert_eq!(base.get("incremental"), Some(&Value::Boolean(true)));
+    assert_eq!(base.get("skip_fragments"), None);
+    assert_eq!(base.get("profiles"), None);
+
+    let merged = crate::apply_profile(&table, Some("ci"))?;
+    assert_eq!(merged.get("incremental"), Some(&Value::Boolean(false)));
+    assert_eq!(merged.get("skip_fragments"), Some(&Value::Boolean(true)));
+    assert_eq!(merged.get("terminal_colors"), Some(&Value::Boolean(false)));
+
+    assert!(crate::apply_profile(&table, Some("missing")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn results_are_sorted_by_location() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("order_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+
+    let locations = crate::sorted_results(&result)
+        .into_iter()
+        .map(|(test, _)| (test.file.as_str(), test.line))
+        .collect::<Vec<_>>();
+    // In the book's order, which isn't the order of the paths.
+    assert_eq!(
+        locations,
+        [
+            ("chapter_2.md", 6),
+            ("chapter_2.md", 10),
+            ("chapter_10.md", 6),
+            ("chapter_10.md", 10),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn accumulate_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("accumulate_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(String::from("accumulate"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 4);
+    for (test, result) in &result {
+        if test.compile_fail {
+            assert!(matches!(result, TestResult::CompileFailed(_)));
+        } else {
+            assert!(matches!(result, TestResult::Successful(_)));
+        }
+    }
+
+    let (tmp_dir, mut book) = get_starting_directories("accumulate_book")?;
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    let failed = result
+        .values()
+        .filter(|r| matches!(r, TestResult::CompileFailed(_)))
+        .count();
+    assert_eq!(failed, 3);
+
+    Ok(())
+}
+
+#[test]
+fn harness_book_ignored_tests() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("harness_book")?;
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    let harness_result = result.values().next().unwrap();
+    assert!(harness_result.is_success());
+    assert!(harness_result.stdout().unwrap().contains("1 ignored"));
+
+    let (tmp_dir, mut book) = get_starting_directories("harness_book")?;
+    let mut table = Table::new();
+    table.insert(String::from("run_ignored_tests"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(matches!(
+        result.values().next(),
+        Some(TestResult::RunFailed(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn compile_fail_error_codes_are_recorded() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let compile_fail = result.keys().find(|test| test.compile_fail).unwrap();
+
+    let error_codes = crate::cache::read_error_codes(&root_tempdir.join("doctest_cache"));
+    assert_eq!(error_codes.len(), 1);
+    assert_eq!(
+        error_codes.get(&compile_fail.hash),
+        Some(&vec!["E0425".to_string()])
+    );
+
+    // A block added above renames every test after it, but the codes
+    // are still found for the same block.
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut chapter) = item {
+            chapter.content = format!(
+                "```rust,compile_fail\nfn main() {{ let x: u8 = \"\"; }}\n```\n\n{}",
+                chapter.content
+            );
+        }
+    });
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.keys().all(|test| test.name != compile_fail.name));
+    let error_codes = crate::cache::read_error_codes(&root_tempdir.join("doctest_cache"));
+    assert_eq!(error_codes.len(), 2);
+    assert_eq!(
+        error_codes.get(&compile_fail.hash),
+        Some(&vec!["E0425".to_string()])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn locked_build_without_lockfile_fails() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("edition_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let cargo_dir = root_tempdir.join("edition_book").join("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(String::from("locked"), Value::Boolean(true));
+    table.insert(String::from("offline"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book);
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("`cargo build` failed"));
+    assert!(error.contains("--locked"));
+    assert!(!cargo_dir.join("Cargo.lock").exists());
+
+    Ok(())
+}
+
+#[test]
+fn prelude_book_needs_edition() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("prelude_book")?;
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    let (tmp_dir, mut book) = get_starting_directories("prelude_book")?;
+    let mut table = Table::new();
+    table.insert(String::from("edition"), Value::String("2021".to_string()));
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn link_book_no_run_links() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("link_book")?;
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    let (tmp_dir, mut book) = get_starting_directories("link_book")?;
+    let mut table = Table::new();
+    table.insert(String::from("no_run_links"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    Ok(())
+}
+
+#[test]
+fn wrapper_template_wraps_code() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    // Without the wrapper, `TryInto` isn't in scope.
+    let (tmp_dir, mut book) = get_starting_directories("prelude_book")?;
+    let mut table = Table::new();
+    table.insert(
+        String::from("wrapper_template"),
+        Value::String("use std::convert::TryInto;\n{code}".to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // Changing the wrapper changes the code compiled, so it's run again.
+    table.insert(
+        String::from("wrapper_template"),
+        Value::String("use std::convert::TryInto;\n\n{code}".to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    let (tmp_dir, mut book) = get_starting_directories("prelude_book")?;
+    let mut table = Table::new();
+    table.insert(
+        String::from("wrapper_template"),
+        Value::String("fn main() {}".to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book);
+    assert!(result.unwrap_err().to_string().contains("{code}"));
+
+    Ok(())
+}
+
+#[test]
+fn continued_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("continued_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 2);
+    for (test, result) in &result {
+        if test.name.contains("another_section") {
+            assert!(matches!(result, TestResult::CompileFailed(_)));
+        } else {
+            assert_eq!(test.text.len(), 4);
+            assert!(matches!(result, TestResult::Successful(_)));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn misspelled_extern_is_reported() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("long_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("long_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("externs"),
+        Value::Array(vec![Value::String("nomm".to_string())]),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book);
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("extern 'nomm' is not a dependency of the project"));
+    assert!(error.contains("did you mean 'nom'?"));
+
+    Ok(())
+}
+
+#[test]
+fn should_panic_pattern_checks_message() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+
+    let (test, result) = result
+        .iter()
+        .find(|(test, _)| test.text.join("").contains("// panic-ok"))
+        .expect("the should_panic test was run");
+    assert!(result.met_test_expectations(test, Some(&regex::Regex::new(":\\(")?)));
+    assert!(!result.met_test_expectations(test, Some(&regex::Regex::new("^Error: ")?)));
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("should_panic_pattern"),
+        Value::String("(unclosed".to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book);
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("`should_panic_pattern` is not a valid regex"));
+
+    Ok(())
+}
+
+#[test]
+fn path_dep_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("path_dep_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("path_dep_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn rustdoc_book() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("rustdoc_book")?;
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    let (tmp_dir, mut book) = get_starting_directories("rustdoc_book")?;
+    let mut table = Table::new();
+    table.insert(String::from("rustdoc_compat"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 3);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn compile_and_run_separately() -> Result<(), Error> {
+    let (tmp_dir, book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    assert!(!tests.is_empty());
+
+    let mut compiled_tests = Vec::new();
+    for test in &tests {
+        let testcase_path = crate::write_test(test, &config)?;
+        let compiled = crate::compile_test(
+            &config,
+            test,
+            config.target_triple(),
+            &testcase_path,
+            crate::CompileType::Full,
+        )
+        .expect("the test should compile");
+        assert!(compiled.binary_path.is_file());
+        compiled_tests.push(compiled);
+    }
+
+    for compiled in &compiled_tests {
+        let result = crate::run_compiled(compiled, &config);
+        assert!(matches!(result, TestResult::Successful(_)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn forbid_patterns_are_checked() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let mut table = Table::new();
+    // Only the block which doesn't compile in short_book uses `asdf`.
+    table.insert(
+        String::from("forbid_patterns"),
+        Value::Array(vec![Value::String("asdf".to_string())]),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book);
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("chapter_1.md:"));
+    assert!(error.contains("`asdf`"));
+
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    table.insert(
+        String::from("forbid_level"),
+        Value::String("warn".to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 7);
+
+    // Comments (like `// ignore-me`) and strings (like "Yeet.") aren't code.
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let mut table = Table::new();
+    table.insert(
+        String::from("forbid_patterns"),
+        Value::Array(vec![
+            Value::String("ignore-me".to_string()),
+            Value::String("Yeet".to_string()),
+        ]),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 7);
+
+    Ok(())
+}
+
+#[test]
+fn dependency_changes_invalidate_cache() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("path_dep_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("path_dep_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    // Bumping a dependency's version changes the lockfile, and its rlib.
+    let greeter_manifest = cargo_dir.join("greeter").join("Cargo.toml");
+    let manifest = std::fs::read_to_string(&greeter_manifest)?;
+    std::fs::write(&greeter_manifest, manifest.replace("0.1.0", "0.2.0"))?;
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    for (test, result) in &result {
+        if test.text.join("").contains("greeter") {
+            assert!(matches!(result, TestResult::Successful(_)));
+        } else {
+            assert!(matches!(result, TestResult::Cached));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn replace_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("replace_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 3);
+    assert!(result
+        .values()
+        .all(|res| matches!(res, TestResult::Successful(_))));
+
+    let BookItem::Chapter(ref chapter) = book.sections[0] else {
+        panic!("Expected a chapter");
+    };
+    assert!(chapter.content.contains("// visible"));
+    assert!(chapter.content.contains("// visible-sum"));
+    assert!(!chapter.content.contains("// replacement"));
+
+    Ok(())
+}
+
+#[test]
+fn unfinished_tests_are_not_cached() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cache_dir = root_tempdir.join("doctest_cache");
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let hash = result.keys().next().unwrap().hash.clone();
+
+    // Pretend keeper crashed while running this test.
+    let cached_path = cache_dir.join(format!("keeper_{}.rs", hash));
+    let pending_path = cache_dir.join(format!("pending_{}.rs", hash));
+    std::fs::rename(&cached_path, &pending_path)?;
+
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let (_, rerun) = result.iter().find(|(test, _)| test.hash == hash).unwrap();
+    assert!(matches!(rerun, TestResult::Successful(_)));
+    assert!(cached_path.is_file());
+    assert!(!pending_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn no_std_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("no_std_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 2);
+    for (test, result) in &result {
+        assert!(test.no_run);
+        if test.text.join("").contains("// no-std") {
+            assert!(result.is_success());
+        } else {
+            assert_eq!(result.kind(), crate::Outcome::CompileFailed);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn alias_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("alias_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("alias_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("externs"),
+        Value::Array(vec![Value::String(String::from("hello=greeter"))]),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn unspawnable_binary_is_reported() -> Result<(), Error> {
+    let (tmp_dir, book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    let test = &tests[0];
+
+    let testcase_path = crate::write_test(test, &config)?;
+    let compiled = crate::compile_test(
+        &config,
+        test,
+        config.target_triple(),
+        &testcase_path,
+        crate::CompileType::Full,
+    )
+    .expect("the test should compile");
+    std::fs::remove_file(&compiled.binary_path)?;
+
+    let result = crate::run_compiled(&compiled, &config);
+    assert_eq!(result.kind(), crate::Outcome::SpawnFailed);
+    assert!(!result.met_test_expectations(test, None));
+
+    Ok(())
+}
+
+#[test]
+fn shard_cache() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cache_dir = root_tempdir.join("doctest_cache");
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(String::from("shard_cache"), Value::Boolean(true));
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    for test in result.keys() {
+        let shard = cache_dir.join(&test.hash[..2]);
+        assert!(shard.join(format!("keeper_{}.rs", test.hash)).is_file());
+        assert!(!cache_dir.join(format!("keeper_{}.rs", test.hash)).exists());
+    }
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    // Going back to the flat layout removes the sharded files.
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    for test in result.keys() {
+        let shard = cache_dir.join(&test.hash[..2]);
+        assert!(!shard.join(format!("keeper_{}.rs", test.hash)).exists());
+        assert!(cache_dir.join(format!("keeper_{}.rs", test.hash)).is_file());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn fixture_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("fixture_book")?;
+    let root_tempdir = tmp_dir.path();
+    let fixture_dir = root_tempdir.join("fixture_book").join("fixture");
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(
+        String::from("fixture_dir"),
+        Value::String(fixture_dir.display().to_string()),
+    );
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+    assert!(fixture_dir.join("data").join("greeting.txt").is_file());
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    // Changing the fixture means the tests are run again.
+    std::fs::write(fixture_dir.join("data").join("extra.txt"), "extra")?;
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn unbalanced_hidden_lines_get_a_hint() {
+    use crate::skeptic::hidden_line_hint;
+
+    let lines = |code: &str| code.lines().map(|l| format!("{}\n", l)).collect::<Vec<_>>();
+
+    assert_eq!(
+        hidden_line_hint(&lines("# fn main() {\nlet x = 1;\n# }")),
+        None
+    );
+    // Braces in strings and comments don't count.
+    assert_eq!(
+        hidden_line_hint(&lines("fn main() {\n    println!(\"{{\"); // }\n}")),
+        None
+    );
+
+    let hint = hidden_line_hint(&lines("# fn main() {\nlet x = 1;\n}\n}")).unwrap();
+    assert!(hint.contains("the `}` on line 4"));
+    assert!(hint.contains("no hidden"));
+
+    let hint = hidden_line_hint(&lines("#fn main() {\n#   let x = (1;\n# }")).unwrap();
+    assert!(hint.contains("the `}` on line 3"));
+    assert!(hint.contains("`#fn main() {` looks like a hidden line"));
+}
+
+#[test]
+fn machine_summary_counts_results() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("fragment_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(String::from("skip_fragments"), Value::Boolean(true));
+    table.insert(String::from("machine_summary"), Value::Boolean(true));
+    let config = crate::KeeperConfig::new(Some(&table), root_tempdir)?;
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let summary = crate::summarize(&result, &config);
+    assert_eq!(
+        serde_json::to_string(&summary)?,
+        r#"{"passed":1,"failed":0,"ignored":3,"cached":0}"#
+    );
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let summary = crate::summarize(&result, &config);
+    assert_eq!((summary.passed, summary.cached), (0, 1));
+
+    Ok(())
+}
+
+#[test]
+fn out_dir_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("out_dir_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("out_dir_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn build_project_can_be_disabled() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("path_dep_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("path_dep_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(String::from("build_project"), Value::Boolean(false));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    // Nothing was built, so the example using dependencies can't find them.
+    let target_dir = root_tempdir.join("doctest_cache").join("target");
+    assert!(!target_dir.join("debug").exists());
+    let failures = result
+        .values()
+        .filter(|r| matches!(r, TestResult::CompileFailed(_)))
+        .count();
+    assert_eq!(failures, 1);
+
+    Ok(())
+}
+
+#[test]
+fn directive_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("directive_book")?;
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+
+    let test_list = result
+        .into_iter()
+        .map(|(t, res)| (t.text[0].trim().to_string(), (t, res)))
+        .collect::<HashMap<_, _>>();
+
+    // `//@ ignore` means the block isn't run at all.
+    assert_eq!(test_list.len(), 7);
+    assert!(!test_list.contains_key("// ignore"));
+
+    let (ref test, ref result) = test_list["// compile-fail"];
+    assert!(test.compile_fail);
+    assert!(matches!(result, TestResult::CompileFailed(_)));
+
+    let (ref test, ref result) = test_list["// should-panic"];
+    assert!(test.should_panic);
+    assert!(matches!(result, TestResult::RunFailed(_)));
+
+    let (ref test, ref result) = test_list["// no-run"];
+    assert!(test.no_run);
+    assert!(matches!(result, TestResult::Successful(_)));
+
+    let (ref test, ref result) = test_list["// exit-code"];
+    assert_eq!(test.exit_code, Some(3));
+    assert!(result.met_test_expectations(test, None));
+
+    let (ref test, ref result) = test_list["// edition"];
+    assert_eq!(test.edition.as_deref(), Some("2021"));
+    assert!(matches!(result, TestResult::Successful(_)));
+
+    let (ref test, ref result) = test_list["// several"];
+    assert!(test.no_run);
+    assert_eq!(test.edition.as_deref(), Some("2021"));
+    assert!(matches!(result, TestResult::Successful(_)));
+
+    // Unknown directives are left alone, as comments.
+    let (ref test, ref result) = test_list["//@ not-a-directive"];
+    assert!(matches!(result, TestResult::Successful(_)));
+    assert_eq!(test.text[1].trim(), "// unknown");
+
+    Ok(())
+}
+
+#[test]
+fn missing_target_is_reported_once() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let mut table = Table::new();
+    table.insert(
+        String::from("target"),
+        Value::String(String::from("armv5te-unknown-linux-musleabi")),
+    );
+    let error = bookkeeper
+        .real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("rustup target add armv5te-unknown-linux-musleabi"));
+
+    table.insert(String::from("skip_if_target_missing"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(result.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn output_book() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("output_book")?;
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+
+    let test_list = result
+        .into_iter()
+        .map(|(t, res)| (t.text[0].trim().to_string(), (t, res)))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(test_list.len(), 3);
+
+    let (ref test, ref result) = test_list["// right"];
+    assert_eq!(test.expected_output.as_deref(), Some("Hello,\nworld!\n"));
+    assert!(matches!(result, TestResult::Successful(_)));
+
+    let (ref test, ref result) = test_list["// wrong"];
+    assert!(matches!(result, TestResult::WrongOutput(_)));
+    assert!(!result.met_test_expectations(test, None));
+
+    let (ref test, ref result) = test_list["// unchecked"];
+    assert_eq!(test.expected_output, None);
+    assert!(matches!(result, TestResult::Successful(_)));
+
+    Ok(())
+}
+
+#[test]
+fn explicit_lockfile_is_used() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("path_dep_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("path_dep_book");
+    cargo_dir.push("cargo");
+
+    let lockfile = root_tempdir.join("Other.lock");
+    let lock = |greeter_version: &str| {
+        format!(
+            "version = 3\n\n\
+             [[package]]\nname = \"greeter\"\nversion = \"{}\"\n\n\
+             [[package]]\nname = \"shouter\"\nversion = \"0.1.0\"\n",
+            greeter_version
+        )
+    };
+    std::fs::write(&lockfile, lock("0.1.0"))?;
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("lockfile"),
+        Value::String(lockfile.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    // Changing the given lockfile (rather than the project's) re-runs
+    // the example which uses dependencies.
+    std::fs::write(&lockfile, lock("0.1.1"))?;
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let rerun = result
+        .values()
+        .filter(|r| !matches!(r, TestResult::Cached))
+        .count();
+    assert_eq!(rerun, 1);
+
+    // A lockfile which doesn't exist is an error.
+    table.insert(
+        String::from("lockfile"),
+        Value::String(root_tempdir.join("Missing.lock").display().to_string()),
+    );
+    assert!(bookkeeper
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn compile_budgets() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("budget_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    // Nothing compiles in no time at all.
+    let mut table = Table::new();
+    table.insert(String::from("max_compile_ms"), Value::Integer(0));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    let test_list = result
+        .into_iter()
+        .map(|(t, res)| (t.text[0].trim().to_string(), res))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(
+        test_list["// default"].kind(),
+        crate::Outcome::CompileTooSlow
+    );
+    assert!(matches!(
+        test_list["// own-budget"],
+        TestResult::Successful(_)
+    ));
+
+    // With `warn`, going over budget doesn't stop the test passing.
+    table.insert(
+        String::from("compile_budget_level"),
+        Value::String(String::from("warn")),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_) | TestResult::Cached)));
+
+    Ok(())
+}
+
+#[test]
+fn dump_tests_dir() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let dump_dir = root_tempdir.join("dumped");
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    // Relative to the book's root, not wherever keeper is run from.
+    table.insert(
+        String::from("dump_tests_dir"),
+        Value::String(String::from("dumped")),
+    );
+
+    // Tests are dumped whether or not they were cached.
+    for _ in 0..2 {
+        let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+        assert!(!result.is_empty());
+        for test in result.keys() {
+            let name = crate::skeptic::sanitize_test_name(&test.name);
+            let dumped = std::fs::read_to_string(dump_dir.join(format!("{}.rs", name)))?;
+            let cached = std::fs::read_to_string(
+                root_tempdir
+                    .join("doctest_cache")
+                    .join(format!("keeper_{}.rs", test.hash)),
+            )?;
+            assert_eq!(dumped, cached);
+        }
+        std::fs::remove_dir_all(&dump_dir)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn identical_examples_are_compiled_once() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("duplicate_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    let shared = crate::tests_sharing_code(&tests, &config);
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].len(), 2);
+    assert!(shared[0][0].line < shared[0][1].line);
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let cached = result
+        .values()
+        .filter(|r| matches!(r, TestResult::Cached))
+        .count();
+    assert_eq!((result.len(), cached), (3, 1));
+
+    Ok(())
+}
+
+#[test]
+fn allowed_exit_codes() -> Result<(), Error> {
+    let bookkeeper = BookKeeper::new();
+
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let mut table = Table::new();
+    table.insert(
+        String::from("allowed_exit_codes"),
+        Value::Array(vec![Value::Integer(2)]),
+    );
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+
+    let test_list = result
+        .into_iter()
+        .map(|(t, res)| (t.text[0].trim().to_string(), (t, res)))
+        .collect::<HashMap<_, _>>();
+
+    // The block tagged `exit-2` still has its code checked.
+    let (ref test, ref result) = test_list["// exit-code"];
+    assert!(matches!(result, TestResult::Successful(_)));
+    assert_eq!(result.output().unwrap().status.code(), Some(2));
+    assert!(result.met_test_expectations(test, None));
+
+    // Panics (with code 101) aren't allowed.
+    assert!(matches!(test_list["// panic"].1, TestResult::RunFailed(_)));
+
+    Ok(())
+}
+
+#[test]
+fn edition_tag_without_manifest() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("edition_tag_book")?;
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 1);
+    let (test, result) = result.iter().next().unwrap();
+    assert_eq!(test.edition.as_deref(), Some("2018"));
+    assert!(matches!(result, TestResult::Successful(_)));
+
+    Ok(())
+}
+
+#[test]
+fn glob_externs() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("glob_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("glob_book");
+    cargo_dir.push("cargo");
+
+    let names = crate::run_tests::dependency_names(&cargo_dir, false).unwrap();
+    assert_eq!(
+        crate::run_tests::expand_externs(&[String::from("myorg-*")], &names),
+        vec![String::from("myorg_greet"), String::from("myorg_shout")]
+    );
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("externs"),
+        Value::Array(vec![Value::String(String::from("myorg-*"))]),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // A pattern which matches nothing is a mistake in the config.
+    table.insert(
+        String::from("externs"),
+        Value::Array(vec![Value::String(String::from("nothing-*"))]),
+    );
+    let error = bookkeeper
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .unwrap_err();
+    assert!(error.to_string().contains("nothing-*"));
+
+    Ok(())
+}
+
+#[test]
+fn dependency_lints() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("alias_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("alias_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(
+        String::from("externs"),
+        Value::Array(vec![Value::String(String::from("hello=greeter"))]),
+    );
+
+    // `greeter::greet` has no documentation, so denying `missing_docs`
+    // stops the project building.
+    let mut lints = Table::new();
+    lints.insert(
+        String::from("missing_docs"),
+        Value::String(String::from("deny")),
+    );
+    table.insert(
+        String::from("dependency_lints"),
+        Value::Table(lints.clone()),
+    );
+    let error = bookkeeper
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .unwrap_err();
+    assert!(error.to_string().contains("`cargo build` failed"));
+
+    lints.insert(
+        String::from("missing_docs"),
+        Value::String(String::from("allow")),
+    );
+    table.insert(String::from("dependency_lints"), Value::Table(lints));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // Levels rustc doesn't have are a mistake in the config.
+    let mut lints = Table::new();
+    lints.insert(
+        String::from("missing_docs"),
+        Value::String(String::from("ignore")),
+    );
+    table.insert(String::from("dependency_lints"), Value::Table(lints));
+    assert!(bookkeeper
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .is_err());
+
+    Ok(())
+}
+
+/// Tests taken straight from a string of markdown, as another
+/// documentation tool might provide them.
+struct MarkdownSource(&'static str);
+
+impl crate::TestSource for MarkdownSource {
+    fn tests(&self, config: &crate::KeeperConfig) -> Result<Vec<crate::Test>, Error> {
+        Ok(crate::tests_from_markdown(
+            self.0,
+            "markdown_source",
+            config,
+        ))
+    }
+}
+
+#[test]
+fn tests_from_another_source() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = MarkdownSource(
+        "```rust\nfn main() {}\n```\n\n```rust,should_panic\nfn main() { panic!() }\n```\n",
+    );
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run_with_source(None, tmp_dir.path().to_path_buf(), source)?;
+
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .iter()
+        .all(|(test, r)| r.met_test_expectations(test, None)));
+
+    Ok(())
+}
+
+#[test]
+fn manifest_without_packages() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("empty_workspace_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("empty_workspace_book");
+    cargo_dir.push("cargo");
+
+    // cargo itself refuses to build an empty workspace, so this is
+    // only reachable when keeper doesn't build it.
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    table.insert(String::from("build_project"), Value::Boolean(false));
+
+    let error = BookKeeper::new()
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .unwrap_err();
+    // Newer versions of `cargo metadata` give their own error.
+    assert!(error.to_string().contains("no package"), "{}", error);
+
+    Ok(())
+}
+
+#[test]
+fn manifest_without_known_edition() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("unknown_edition_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let cargo_dir = root_tempdir.join("unknown_edition_book").join("cargo");
+
+    // The only package is on an edition keeper doesn't know, so the
+    // example falls back to 2021 (and would fail on any other edition).
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+
+    let result = BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(matches!(
+        result.into_values().next().unwrap(),
+        TestResult::Successful(_)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn verify_cache() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cache_dir = root_tempdir.join("doctest_cache");
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    for test in result.keys() {
+        let path = cache_dir.join(format!("keeper_{}.rs", test.hash));
+        std::fs::write(&path, "fn main() { broken }")?;
+    }
+
+    // Without `verify_cache`, the edited files are trusted.
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    let mut table = Table::new();
+    table.insert(String::from("verify_cache"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // Running them again put the right code back.
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    Ok(())
+}
+
+#[test]
+fn chapter_attributes() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("mistakes_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    let mut chapters = Table::new();
+    chapters.insert(
+        String::from("mistakes/*.md"),
+        Value::Array(vec![Value::String(String::from("compile_fail"))]),
+    );
+    table.insert(String::from("chapter_attributes"), Value::Table(chapters));
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 4);
+    assert!(result
+        .iter()
+        .all(|(test, r)| r.met_test_expectations(test, None)));
+    for test in result.keys() {
+        let in_mistakes = test.file.starts_with("mistakes");
+        assert_eq!(test.compile_fail, in_mistakes && !test.should_panic);
+    }
+
+    // Only the attributes a block can have may be given.
+    let mut chapters = Table::new();
+    chapters.insert(
+        String::from("mistakes/*.md"),
+        Value::Array(vec![Value::String(String::from("compile-fail"))]),
+    );
+    table.insert(String::from("chapter_attributes"), Value::Table(chapters));
+    assert!(bookkeeper
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn manifest_dir_without_cargo_toml() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(String::from("no_such_project")),
+    );
+    let target_dir = root_tempdir.join("target");
+    table.insert(
+        String::from("target_dir"),
+        Value::String(target_dir.display().to_string()),
+    );
+
+    let error = BookKeeper::new()
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("manifest_dir 'no_such_project' does not contain a Cargo.toml"));
+    let absolute = std::env::current_dir()?.join("no_such_project");
+    assert!(error.contains(&absolute.display().to_string()));
+    // The mistake is found before cargo is ever started.
+    assert!(!target_dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn locale_and_timezone() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("locale_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(
+        String::from("locale"),
+        Value::String(String::from("C.UTF-8")),
+    );
+    table.insert(
+        String::from("timezone"),
+        Value::String(String::from("Asia/Tokyo")),
+    );
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // A different timezone means the example is run again, rather than cached.
+    table.insert(String::from("timezone"), Value::String(String::from("UTC")));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::RunFailed(_))));
+
+    Ok(())
+}
+
+#[test]
+fn only_failed() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let failed = result
+        .iter()
+        .filter(|(test, r)| !r.met_test_expectations(test, None))
+        .count();
+    assert!(failed > 0 && failed < result.len());
+
+    // Removing the cache means every test would normally be run again.
+    for path in std::fs::read_dir(root_tempdir.join("doctest_cache"))? {
+        let path = path?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("rs") {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let mut table = Table::new();
+    table.insert(String::from("only_failed"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let skipped = result
+        .values()
+        .filter(|r| matches!(r, TestResult::Skipped(SkipReason::PassedPreviously)))
+        .count();
+    assert_eq!(skipped, result.len() - failed);
+    assert!(result.values().all(|r| matches!(
+        r,
+        TestResult::Skipped(_) | TestResult::CompileFailed(_) | TestResult::RunFailed(_)
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn run_ignored_reasons() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("ignore_reason_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    let reasons = tests
+        .iter()
+        .map(|test| test.ignore_reason.as_deref())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        reasons,
+        vec![
+            Some("requires-network"),
+            Some("requires-network"),
+            Some("requires-gpu"),
+            None
+        ]
+    );
+    assert!(tests.iter().all(|test| test.ignore));
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 0);
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("run_ignored_reasons"),
+        Value::Array(vec![Value::String(String::from("requires-network"))]),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    Ok(())
+}
+
+#[test]
+fn diagnostics_path() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+    let diagnostics_path = root_tempdir.join("diagnostics.jsonl");
+
+    let mut table = Table::new();
+    // Relative to the book's root, not wherever keeper is run from.
+    table.insert(
+        String::from("diagnostics_path"),
+        Value::String(String::from("diagnostics.jsonl")),
+    );
+    let result = BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    // The report still shows rustc's usual output.
+    let (_, failed) = result
+        .iter()
+        .find(|(_, r)| matches!(r, TestResult::CompileFailed(_)))
+        .expect("a test fails to compile");
+    let stderr = failed.stderr().unwrap_or_default();
+    assert!(stderr.contains("cannot find value `asdf`"));
+    assert!(!stderr.trim_start().starts_with('{'));
+
+    let records = std::fs::read_to_string(&diagnostics_path)?
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let asdf = records
+        .iter()
+        .find(|record| {
+            record["diagnostic"]["message"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("asdf")
+        })
+        .expect("the error is recorded");
+    assert_eq!(asdf["file"], "chapter_1.md");
+    assert_eq!(asdf["diagnostic"]["spans"][0]["chapter_line_start"], 9);
+
+    Ok(())
+}
+
+#[test]
+fn annotate_blocks() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut table = Table::new();
+    table.insert(String::from("annotate_blocks"), Value::Boolean(true));
+    BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    let content = match &book.sections[0] {
+        BookItem::Chapter(chapter) => chapter.content.clone(),
+        _ => panic!("the first section is a chapter"),
+    };
+    let notes = content
+        .lines()
+        .filter(|line| line.contains("keeper-result"))
+        .collect::<Vec<_>>();
+    // The `ignore`d block has no result to show.
+    assert_eq!(notes.len(), 7);
+    assert!(notes[0].contains("keeper-failed"));
+    assert!(notes[1].contains("keeper-passed"));
+
+    // Each note comes straight after the block it's about.
+    let error_block = content.find("asdf").expect("the block is still there");
+    let first_note = content.find("keeper-result").expect("there is a note");
+    assert!(error_block < first_note);
+    assert!(!content[error_block..first_note].contains("```rust"));
+
+    // A block tested in several editions has a note for each.
+    let (tmp_dir, mut book) = get_starting_directories("edition_matrix_book")?;
+    table.insert(
+        String::from("test_editions"),
+        Value::Array(vec![
+            Value::String(String::from("2018")),
+            Value::String(String::from("2021")),
+        ]),
+    );
+    BookKeeper::new().real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    let content = match &book.sections[0] {
+        BookItem::Chapter(chapter) => chapter.content.clone(),
+        _ => panic!("the first section is a chapter"),
+    };
+    let notes = content
+        .lines()
+        .filter(|line| line.contains("keeper-result"))
+        .collect::<Vec<_>>();
+    assert_eq!(notes.len(), 5);
+    assert!(notes[2].contains("keeper-failed") && notes[2].contains("failed in edition 2018"));
+    assert!(notes[3].contains("keeper-passed") && notes[3].contains("passed in edition 2021"));
+    assert!(!notes[4].contains("in edition"));
+
+    // Without the option, the book is left as it was.
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    BookKeeper::new().real_run(None, tmp_dir.path().to_path_buf(), &mut book)?;
+    if let BookItem::Chapter(chapter) = &book.sections[0] {
+        assert!(!chapter.content.contains("keeper-result"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn panics_show_a_backtrace() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+    let panicking = |result: &HashMap<crate::Test, TestResult>| {
+        result
+            .iter()
+            .find(|(test, r)| {
+                !test.should_panic
+                    && matches!(r, TestResult::RunFailed(_))
+                    && test.text.iter().any(|l| l.contains("panic!"))
+            })
+            .map(|(_, r)| r.stderr().unwrap_or_default().to_string())
+            .expect("an example panics")
+    };
+
+    // Without `backtrace`, it would depend on how the tests were run.
+    let mut table = Table::new();
+    table.insert(
+        String::from("backtrace"),
+        Value::String(String::from("short")),
+    );
+    let result = BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(panicking(&result).contains("stack backtrace:"));
+
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    table.insert(
+        String::from("backtrace"),
+        Value::String(String::from("off")),
+    );
+    let result =
+        BookKeeper::new().real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(!panicking(&result).contains("stack backtrace:"));
+
+    Ok(())
+}
+
+#[test]
+fn shared_test_dir_is_not_cleaned() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cache_dir = root_tempdir.join("doctest_cache");
+    let foreign = cache_dir.join("keeper_fromanotherbook.rs");
+
+    let bookkeeper = BookKeeper::new();
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let owner = crate::cache::read_owner(&cache_dir).expect("the cache is claimed");
+
+    // Another book's tests are left alone.
+    std::fs::write(cache_dir.join(crate::cache::OWNER_FILE), "Another Book")?;
+    std::fs::write(&foreign, "fn main() {}")?;
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(foreign.is_file());
+
+    // Even if the cache was written by another version of keeper.
+    std::fs::remove_file(cache_dir.join(crate::cache::INDEX_FILE))?;
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(foreign.is_file());
+    assert_eq!(
+        crate::cache::read_owner(&cache_dir).as_deref(),
+        Some("Another Book")
+    );
+    crate::cache::create_dir(&cache_dir)?;
+
+    // So are unknown tests in a cache nobody has claimed, the first time.
+    std::fs::remove_file(cache_dir.join(crate::cache::OWNER_FILE))?;
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(foreign.is_file());
+    assert_eq!(crate::cache::read_owner(&cache_dir), Some(owner));
+
+    // Once the cache is this book's, they're cleaned up as usual.
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(!foreign.exists());
+
+    Ok(())
+}
+
+#[test]
+fn seed() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("seed_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(String::from("seed"), Value::Integer(42));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // Another seed gives other output, and isn't mistaken for the cached run.
+    table.insert(String::from("seed"), Value::Integer(7));
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::WrongOutput(_))));
+
+    Ok(())
+}
+
+#[test]
+fn test_editions() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("edition_matrix_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("test_editions"),
+        Value::Array(vec![
+            Value::String(String::from("2018")),
+            Value::String(String::from("2021")),
+        ]),
+    );
+    let result = BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    let mut outcomes = result
+        .iter()
+        .map(|(test, r)| (test.name.clone(), r.kind()))
+        .collect::<Vec<_>>();
+    outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+    let names = outcomes
+        .iter()
+        .map(|(name, _)| name.rsplit('_').next().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["17@2018", "17@2021", "27", "5@2018", "5@2021"]);
+
+    let outcome = |suffix: &str| {
+        outcomes
+            .iter()
+            .find(|(name, _)| name.ends_with(suffix))
+            .map(|(_, outcome)| *outcome)
+    };
+    assert_eq!(outcome("_5@2018"), Some(crate::Outcome::Successful));
+    assert_eq!(outcome("_5@2021"), Some(crate::Outcome::Successful));
+    assert_eq!(outcome("_17@2018"), Some(crate::Outcome::CompileFailed));
+    assert_eq!(outcome("_17@2021"), Some(crate::Outcome::Successful));
+    assert_eq!(outcome("_27"), Some(crate::Outcome::Successful));
+
+    Ok(())
+}
+
+#[test]
+fn chapters_with_the_same_name() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("same_name_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let result = BookKeeper::new().real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 4);
+
+    let mut names = result
+        .keys()
+        .map(|test| test.name.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    assert_eq!(names.len(), 4);
+    assert!(names
+        .iter()
+        .all(|name| name.starts_with("guide/intro.md") || name.starts_with("reference/intro.md")));
+    assert!(result
+        .iter()
+        .all(|(test, r)| r.met_test_expectations(test, None)));
+
+    Ok(())
+}
+
+#[test]
+fn platform_specific_blocks() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("platform_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let result = BookKeeper::new().real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 3);
+
+    // Which blocks are skipped depends on where the tests are run.
+    let windows = current_platform::CURRENT_PLATFORM.contains("windows");
+    for (test, r) in &result {
+        let unix_block = test.only_platforms.contains(&String::from("unix"))
+            || test.ignore_platforms.contains(&String::from("windows"));
+        if unix_block != windows {
+            assert!(matches!(r, TestResult::Successful(_)), "{}", test.name);
+        } else {
+            assert!(
+                matches!(r, TestResult::Skipped(SkipReason::Platform)),
+                "{}",
+                test.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn unchanged_chapters_are_not_parsed_again() -> Result<(), Error> {
+    let (tmp_dir, book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    assert!(!tests.is_empty());
+
+    // Changing what was saved shows whether the chapter was parsed again.
+    let chapters_file = config.test_dir.join(crate::cache::CHAPTERS_FILE);
+    let mut chapters: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&chapters_file)?)?;
+    for chapter in chapters.as_object_mut().unwrap().values_mut() {
+        for test in chapter["tests"].as_array_mut().unwrap() {
+            test["name"] = serde_json::Value::from("from_the_cache");
+        }
+    }
+    std::fs::write(&chapters_file, serde_json::to_string(&chapters)?)?;
+
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    assert!(tests.iter().all(|test| test.name == "from_the_cache"));
+
+    // Once the chapter changes, it's parsed again.
+    let mut book = book;
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut chapter) = item {
+            chapter.content.push_str("\nA new paragraph.\n");
+        }
+    });
+    let tests = crate::get_tests_from_book(&book, &config)?;
+    assert!(tests.iter().all(|test| test.name != "from_the_cache"));
+
+    Ok(())
+}
+
+#[test]
+fn dev_dependencies() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("dev_dependency_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut cargo_dir = root_tempdir.to_path_buf();
+    cargo_dir.push("dev_dependency_book");
+    cargo_dir.push("cargo");
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    table.insert(
+        String::from("include_dev_dependencies"),
+        Value::Boolean(true),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // Build-dependencies are only for the build script.
+    let names = crate::run_tests::dependency_names(&cargo_dir, true).unwrap();
+    assert!(names.contains(&String::from("helper")));
+    assert!(!names.contains(&String::from("builder")));
+
+    Ok(())
+}
+
+#[test]
+fn output_diff_matches_up_lines() {
+    let diff = crate::output_diff("one\ntwo\nthree\n", "one\nextra\ntwo\nthree\n");
+    let lines = diff.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "  one");
+    assert!(lines[1].contains("+ extra"));
+    assert_eq!(lines[2], "  two");
+    assert_eq!(lines[3], "  three");
+
+    let diff = crate::output_diff("Hello!\n", "Goodbye!\n");
+    let lines = diff.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("- Hello!"));
+    assert!(lines[1].contains("+ Goodbye!"));
+
+    // Too many lines differ to match them up, so they're compared in place.
+    let expected = (0..2000).map(|i| format!("{}\n", i)).collect::<String>();
+    let actual = (0..2000)
+        .map(|i| format!("{}\n", i + 1))
+        .collect::<String>();
+    let diff = crate::output_diff(
+        &format!("start\n{}end\n", expected),
+        &format!("start\n{}end\n", actual),
+    );
+    let lines = diff.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4002);
+    assert_eq!(lines[0], "  start");
+    assert!(lines[1].contains("- 0"));
+    assert!(lines[2].contains("+ 1"));
+    assert_eq!(lines[4001], "  end");
+}
+
+#[test]
+fn read_only_cache() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+
+    let mut table = Table::new();
+    table.insert(String::from("read_only_cache"), Value::Boolean(true));
+    for _ in 0..2 {
+        let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+        assert_eq!(result.len(), 7);
+        assert!(!result.values().any(|r| matches!(r, TestResult::Cached)));
+    }
+    assert!(!root_tempdir.join("doctest_cache").exists());
+
+    Ok(())
+}
+
+#[test]
+fn config_from_a_builder() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("alias_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfigBuilder::new()
+        .manifest_dir(root_tempdir.join("alias_book").join("cargo"))
+        .externs(["hello=greeter"])
+        .edition("2021")
+        .terminal_colors(false)
+        .build(root_tempdir)?;
+
+    let result = BookKeeper::new().real_run_with_config(&config, &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // Options without a method of their own are set by name.
+    let mut lints = Table::new();
+    lints.insert(String::from("greeter"), Value::from("deny"));
+    let config = crate::KeeperConfigBuilder::new()
+        .option("run_iterations", 3)
+        .option("should_panic_any_failure", true)
+        .option("dependency_lints", lints)
+        .build(root_tempdir)?;
+    assert_eq!(config.run_iterations, 3);
+    assert!(config.should_panic_any_failure);
+    assert_eq!(config.dependency_lints.len(), 1);
+
+    // And checked, as they would be in `book.toml`.
+    assert!(crate::KeeperConfigBuilder::new()
+        .option("run_iterations", "three")
+        .build(root_tempdir)
+        .is_err());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn rustc_wrapper() -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    // Records what it's asked to run, then runs it.
+    let log = root_tempdir.join("wrapper.log");
+    let wrapper = root_tempdir.join("wrapper.sh");
+    std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\necho \"$1\" >> '{}'\nexec \"$@\"\n",
+            log.display()
+        ),
+    )?;
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("rustc_wrapper"),
+        Value::String(wrapper.display().to_string()),
+    );
+    let result = BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 7);
+
+    let log = std::fs::read_to_string(log)?;
+    assert!(!log.is_empty());
+    assert!(log.lines().all(|line| line.ends_with("rustc")));
+
+    Ok(())
+}
+
+#[test]
+fn unicode_headings_name_tests() {
+    let markdown = "# はじめに\n\n```rust\nfn main() {}\n```\n\n\
+                    # Überblick\n\n```rust\nfn main() {}\n```\n\n\
+                    # 概要\n\n```rust\nfn main() {}\n```\n\n\
+                    # ---\n\n```rust\nfn main() {}\n```\n";
+    let (tests, _) = crate::skeptic::extract_tests_from_string(markdown, "intro", false, false);
+    let names = tests
+        .iter()
+        .map(|test| test.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "intro_sect_hazimeni_line_3",
+            "intro_sect_uberblick_line_9",
+            "intro_sect_gai_yao_line_15",
+            "intro_line_21",
+        ]
+    );
+
+    assert_eq!(
+        crate::skeptic::sanitize_test_name("Chapter 1: Hello!"),
+        "chapter_1_hello"
+    );
+}
+
+#[test]
+fn check_only_still_runs_should_panic() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = || {
+        MarkdownSource(
+            "```rust\nfn main() { panic!(\"not run\") }\n```\n\n\
+             ```rust,should_panic\nfn main() { panic!() }\n```\n\n\
+             ```rust,should_panic\nfn main() {}\n```\n",
+        )
+    };
+    let outcomes = |result: HashMap<crate::Test, TestResult>| {
+        let mut outcomes = result
+            .iter()
+            .map(|(test, r)| (test.line, r.met_test_expectations(test, None)))
+            .collect::<Vec<_>>();
+        outcomes.sort();
+        outcomes.into_iter().map(|(_, met)| met).collect::<Vec<_>>()
+    };
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(String::from("check_only"), Value::Boolean(true));
+    let result =
+        bookkeeper.real_run_with_source(Some(&table), tmp_dir.path().to_path_buf(), source())?;
+    // The first block's panic isn't found, but the `should_panic` blocks
+    // are run, so the one which doesn't panic fails.
+    assert_eq!(outcomes(result), [true, true, false]);
+
+    let result = bookkeeper.real_run_with_source(None, tmp_dir.path().to_path_buf(), source())?;
+    assert_eq!(outcomes(result), [false, true, false]);
+
+    Ok(())
+}
+
+#[test]
+fn edition_override() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("edition_tag_book")?;
+
+    // The block is tagged 2018, but uses `async fn`, which 2015 lacks.
+    let mut table = Table::new();
+    table.insert(String::from("edition"), Value::String(String::from("2015")));
+    table.insert(String::from("verbose"), Value::Boolean(true));
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    table.insert(String::from("edition_override"), Value::Boolean(true));
+    let result = bookkeeper.real_run(Some(&table), tmp_dir.path().to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    Ok(())
+}
+
+#[test]
+fn rustdoc_include() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("include_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    // Without mdbook's `links` preprocessor, the links are left in the blocks.
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    // Once they're expanded, the lines outside each anchor are hidden,
+    // and each block is the whole file.
+    let md = mdbook::MDBook::load(root_tempdir.join("include_book"))?;
+    let (mut book, _) = md.preprocess_book(&mdbook::renderer::HtmlHandlebars::new())?;
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 2);
+    assert!(result.iter().all(|(test, r)| test
+        .text
+        .iter()
+        .any(|line| line.starts_with("# fn main()"))
+        && r.met_test_expectations(test, None)));
+
+    Ok(())
+}
+
+#[test]
+fn default_crate_type_lib() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = || {
+        MarkdownSource(
+            "```rust\nfn first(v: &[u8]) -> u8 { v[0] }\nfn main() { first(&[]); }\n```\n\n\
+             ```rust,should_panic\nfn main() { panic!(\"run\") }\n```\n",
+        )
+    };
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(
+        String::from("default_crate_type"),
+        Value::String(String::from("lib")),
+    );
+    let result =
+        bookkeeper.real_run_with_source(Some(&table), tmp_dir.path().to_path_buf(), source())?;
+    assert_eq!(result.len(), 2);
+    assert!(result
+        .iter()
+        .all(|(test, r)| r.met_test_expectations(test, None)));
+    assert!(result
+        .iter()
+        .any(|(test, r)| test.should_panic && matches!(r, TestResult::RunFailed(_))));
+
+    // As a binary, the first block is run, and panics.
+    let result = bookkeeper.real_run_with_source(None, tmp_dir.path().to_path_buf(), source())?;
+    assert!(result
+        .iter()
+        .any(|(test, r)| !test.should_panic && matches!(r, TestResult::RunFailed(_))));
+
+    Ok(())
+}
+
+#[test]
+fn should_panic_needs_a_panic() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = || {
+        MarkdownSource(
+            "```rust,should_panic\nfn main() { std::process::exit(1) }\n```\n\n\
+             ```rust,should_panic\nfn main() { panic!(\"it panics\") }\n```\n",
+        )
+    };
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run_with_source(None, tmp_dir.path().to_path_buf(), source())?;
+    let mut outcomes = result
+        .iter()
+        .map(|(test, r)| {
+            let output = r.output().unwrap();
+            (
+                test.line,
+                crate::run_tests::describe_exit(output),
+                r.met_test_expectations(test, None),
+            )
+        })
+        .collect::<Vec<_>>();
+    outcomes.sort();
+    assert_eq!(
+        outcomes,
+        [
+            (2, String::from("Exited with code 1"), false),
+            (6, String::from("Panicked"), true)
+        ]
+    );
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("should_panic_any_failure"),
+        Value::Boolean(true),
+    );
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    let result =
+        bookkeeper.real_run_with_source(Some(&table), tmp_dir.path().to_path_buf(), source())?;
+    assert!(result
+        .iter()
+        .all(|(test, r)| config.met_expectations(test, r)));
+
+    Ok(())
+}
+
+#[test]
+fn hash_length() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("short_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    // Deep enough that the files of a test, named with its whole hash,
+    // would go over Windows' limit of 260 characters.
+    let mut test_dir = root_tempdir.join("cache");
+    while test_dir.as_os_str().len() < 200 {
+        test_dir.push("nested_directory");
+    }
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("test_dir"),
+        Value::String(test_dir.display().to_string()),
+    );
+    table.insert(String::from("hash_length"), Value::Integer(16));
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let passed = result
+        .iter()
+        .filter(|(test, r)| r.met_test_expectations(test, None))
+        .count();
+    assert!(passed < result.len());
+
+    let files = crate::cache::cache_files(&test_dir, "keeper_*.rs").collect::<Vec<_>>();
+    assert_eq!(files.len(), passed);
+    for file in &files {
+        assert_eq!(file.file_name().unwrap().len(), "keeper_.rs".len() + 16);
+        assert!(file.as_os_str().len() < 260);
+    }
+
+    // The shortened names are still found in the cache, and kept.
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let cached = result
+        .values()
+        .filter(|r| matches!(r, TestResult::Cached))
+        .count();
+    assert_eq!(cached, passed);
+    assert_eq!(
+        crate::cache::cache_files(&test_dir, "keeper_*.rs").count(),
+        passed
+    );
+
+    table.insert(String::from("hash_length"), Value::Integer(0));
+    assert!(crate::KeeperConfig::new(Some(&table), root_tempdir).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn collect_tests_without_compiling() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = MarkdownSource(
+        "```rust\n# fn hidden() -> u8 { 1 }\nfn main() {\n    assert_eq!(hidden(), 1);\n}\n```\n",
+    );
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("wrapper_template"),
+        Value::String(String::from("#![allow(dead_code)]\n{code}")),
+    );
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    let collected = crate::collect_tests(source, &config)?;
+    assert_eq!(collected.len(), 1);
+
+    let (test, source) = &collected[0];
+    assert_eq!(test.name(), "markdown_source_line_1");
+    assert_eq!(
+        test.visible_code(),
+        "fn main() {\n    assert_eq!(hidden(), 1);\n}\n"
+    );
+    assert!(source.starts_with("#![allow(dead_code)]\nfn hidden() -> u8 { 1 }\nfn main() {"));
+
+    // Nothing was compiled.
+    assert!(!config.test_dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn run_size_limit_lines() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = || {
+        MarkdownSource(
+            "```rust\nfn main() {\n    let v: Vec<u8> = Vec::new();\n    v[0];\n}\n```\n\n\
+             ```rust\nfn main() { panic!(\"short\") }\n```\n",
+        )
+    };
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(String::from("run_size_limit_lines"), Value::Integer(2));
+    let result =
+        bookkeeper.real_run_with_source(Some(&table), tmp_dir.path().to_path_buf(), source())?;
+    assert_eq!(result.len(), 2);
+    // The long block is only checked, so its panic isn't found.
+    assert!(result
+        .iter()
+        .any(|(test, r)| test.text.len() == 4 && matches!(r, TestResult::Successful(_))));
+    assert!(result
+        .iter()
+        .any(|(test, r)| test.text.len() == 1 && matches!(r, TestResult::RunFailed(_))));
+
+    // Without the limit, both are run.
+    let result = bookkeeper.real_run_with_source(None, tmp_dir.path().to_path_buf(), source())?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::RunFailed(_))));
+
+    Ok(())
+}
+
+#[test]
+fn deps_manifest() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let libs_dir = tmp_dir.path().join("libs");
+    std::fs::create_dir(&libs_dir)?;
+    std::fs::write(
+        libs_dir.join("prebuilt.rs"),
+        "pub fn answer() -> u32 { 42 }\n",
+    )?;
+    let status = std::process::Command::new("rustc")
+        .args([
+            "--crate-type=rlib",
+            "--crate-name=prebuilt",
+            "--edition=2021",
+        ])
+        .arg(libs_dir.join("prebuilt.rs"))
+        .arg("-o")
+        .arg(libs_dir.join("libprebuilt.rlib"))
+        .status()?;
+    assert!(status.success());
+
+    // Paths in the manifest are relative to it.
+    let deps_manifest = libs_dir.join("deps.json");
+    std::fs::write(&deps_manifest, r#"{"prebuilt": "libprebuilt.rlib"}"#)?;
+
+    let source =
+        MarkdownSource("```rust\nfn main() {\n    assert_eq!(prebuilt::answer(), 42);\n}\n```\n");
+    let mut table = Table::new();
+    table.insert(
+        String::from("deps_manifest"),
+        Value::String(deps_manifest.display().to_string()),
+    );
+    let bookkeeper = BookKeeper::new();
+    let result =
+        bookkeeper.real_run_with_source(Some(&table), tmp_dir.path().to_path_buf(), source)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    std::fs::write(&deps_manifest, r#"{"prebuilt": "libmissing.rlib"}"#)?;
+    assert!(crate::KeeperConfig::new(Some(&table), tmp_dir.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn status_changes() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let config = crate::KeeperConfig::new(None, tmp_dir.path())?;
+    let bookkeeper = BookKeeper::new();
+    let run = |source: &'static str| -> Result<crate::StatusChanges, Error> {
+        let (results, _) = bookkeeper.run_with_config(&config, MarkdownSource(source))?;
+        Ok(crate::record_statuses(&config, &results))
+    };
+
+    // Everything would be new the first time, so nothing is reported.
+    let changes = run("```rust\nfn main() { assert!(true) }\n```\n\n\
+                       ```rust\nfn main() { println!(\"two\") }\n```\n")?;
+    assert_eq!(changes, crate::StatusChanges::default());
+
+    let changes = run("```rust\nfn main() { assert!(false) }\n```\n\n\
+                       ```rust\nfn main() { println!(\"two\") }\n```\n\n\
+                       ```rust\nfn main() { println!(\"three\") }\n```\n")?;
+    assert_eq!(changes.newly_failing, ["markdown_source_line_1"]);
+    assert!(changes.newly_passing.is_empty());
+    assert_eq!(changes.added, ["markdown_source_line_9"]);
+    assert!(changes.removed.is_empty());
+
+    let changes = run("```rust\nfn main() { assert!(1 == 1) }\n```\n")?;
+    assert_eq!(changes.newly_passing, ["markdown_source_line_1"]);
+    assert!(changes.newly_failing.is_empty());
+    assert_eq!(
+        changes.removed,
+        ["markdown_source_line_5", "markdown_source_line_9"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn run_iterations() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let mut table = Table::new();
+    table.insert(String::from("run_iterations"), Value::Integer(3));
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+
+    // Tests run in `test_dir`, so the second run of this one fails.
+    let source = MarkdownSource(
+        "```rust\nfn main() { println!(\"timed\") }\n```\n\n\
+         ```rust\nfn main() {\n    \
+         assert!(!std::path::Path::new(\"ran_once\").exists());\n    \
+         std::fs::write(\"ran_once\", \"\").unwrap();\n}\n```\n",
+    );
+    let (results, timings) = BookKeeper::new().run_with_config(&config, source)?;
+    assert_eq!(results.len(), 2);
+
+    let timed = results
+        .iter()
+        .find(|(_, r)| matches!(r, TestResult::Successful(_)))
+        .map(|(test, _)| test)
+        .unwrap();
+    let run_times = timings.runs[&timed.name];
+    assert_eq!(run_times.iterations, 3);
+    assert!(run_times.min <= run_times.median && run_times.median <= run_times.max);
+
+    // A failure on any run fails the test, without any times.
+    let (failed, _) = results
+        .iter()
+        .find(|(_, r)| matches!(r, TestResult::RunFailed(_)))
+        .unwrap();
+    assert!(!timings.runs.contains_key(&failed.name));
+
+    Ok(())
+}
+
+#[test]
+fn old_template_is_found_but_not_applied() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("old_template_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let (tests, templates) = crate::get_tests_and_templates_from_book(&book, &config)?;
+    assert_eq!(tests.len(), 1);
+    assert_eq!(
+        templates.old.get("chapter_1.md").map(String::as_str),
+        Some("fn main() {{ {} }}\n")
+    );
+
+    // The template isn't applied, so the block is tested as it is.
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::CompileFailed(_))));
+
+    Ok(())
+}
+
+#[test]
+fn main_wrapper() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = || {
+        MarkdownSource(
+            "```rust\nlet n: u32 = \"42\".parse()?;\nassert_eq!(n, 42);\n```\n\n\
+             ```rust\nstd::mem::drop(())\n```\n",
+        )
+    };
+    let passed = |main_wrapper: Option<&str>| -> Result<Vec<usize>, Error> {
+        let mut table = Table::new();
+        table.insert(String::from("rustdoc_compat"), Value::Boolean(true));
+        if let Some(main_wrapper) = main_wrapper {
+            table.insert(
+                String::from("main_wrapper"),
+                Value::String(String::from(main_wrapper)),
+            );
+        }
+        let result = BookKeeper::new().real_run_with_source(
+            Some(&table),
+            tmp_dir.path().to_path_buf(),
+            source(),
+        )?;
+        let mut passed = result
+            .iter()
+            .filter(|(_, r)| matches!(r, TestResult::Successful(_) | TestResult::Cached))
+            .map(|(test, _)| test.line)
+            .collect::<Vec<_>>();
+        passed.sort();
+        Ok(passed)
+    };
+
+    // Like rustdoc, `main` only returns a `Result` if the block ends in `(())`.
+    assert!(passed(None)?.is_empty());
+    assert_eq!(passed(Some("unit"))?, [7]);
+    assert_eq!(passed(Some("result"))?, [2]);
+
+    // A custom `main` returns its own type, and imports what it needs.
+    let mut wrapper = Table::new();
+    wrapper.insert(
+        String::from("return_type"),
+        Value::String(String::from("std::fmt::Result")),
+    );
+    wrapper.insert(
+        String::from("uses"),
+        Value::Array(vec![Value::String(String::from("std::fmt::Write"))]),
+    );
+    let mut table = Table::new();
+    table.insert(String::from("rustdoc_compat"), Value::Boolean(true));
+    table.insert(String::from("main_wrapper"), Value::Table(wrapper));
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    let collected = crate::collect_tests(source(), &config)?;
+    assert!(collected[0]
+        .1
+        .contains("use std::fmt::Write;\nfn main() -> std::fmt::Result {\n"));
+    assert!(collected[0].1.ends_with("assert_eq!(n, 42);\nOk(())\n}\n"));
+
+    Ok(())
+}
+
+#[test]
+fn clear_cache() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let test_dir = root_tempdir.join("doctest_cache");
+
+    let bookkeeper = BookKeeper::new();
+    bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    std::fs::write(test_dir.join("unrelated.txt"), "not keeper's")?;
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.values().all(|r| matches!(r, TestResult::Cached)));
+
+    let mut table = Table::new();
+    table.insert(String::from("clear_cache"), Value::Boolean(true));
+    // Only running the tests clears the cache, not reading the config.
+    crate::KeeperConfig::new(Some(&table), root_tempdir)?;
+    assert!(crate::cache::cache_files(&test_dir, "keeper_*.rs").count() > 0);
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(!result.is_empty());
+    assert!(result.values().all(|r| !matches!(r, TestResult::Cached)));
+    assert!(test_dir.join("unrelated.txt").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn errors_point_at_chapter_lines() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    // The crate attribute is moved out of the `main` rustdoc adds.
+    let source = MarkdownSource(
+        "```rust\n# use std::fmt::Write;\n#![allow(dead_code)]\nlet x: u32 = \"no\";\n```\n\n\
+         ```rust\nfn main() {\n    panic!(\"at line 9\");\n}\n```\n",
+    );
+    let mut table = Table::new();
+    table.insert(String::from("rustdoc_compat"), Value::Boolean(true));
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    let (results, _) = BookKeeper::new().run_with_config(&config, source)?;
+    assert_eq!(results.len(), 2);
+
+    for (test, result) in &results {
+        let stderr = result.stderr().unwrap_or_default();
+        let stderr = crate::chapter_locations(&stderr, test, &config);
+        match result {
+            TestResult::CompileFailed(_) => assert!(stderr.contains("markdown_source:4:")),
+            TestResult::RunFailed(_) => assert!(stderr.contains("markdown_source:9:")),
+            _ => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn override_file() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let mut table = Table::new();
+    table.insert(String::from("incremental"), Value::Boolean(true));
+    table.insert(String::from("rustdoc_compat"), Value::Boolean(false));
+
+    // Options in `.keeper.toml` replace those in `book.toml`.
+    std::fs::write(
+        tmp_dir.path().join(".keeper.toml"),
+        "rustdoc_compat = true\nverify_cache = true\n",
+    )?;
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    assert!(config.rustdoc_compat);
+    assert!(config.verify_cache);
+    assert!(config.incremental);
+
+    // Even without a `[preprocessor.keeper]` table.
+    let config = crate::KeeperConfig::new(None, tmp_dir.path())?;
+    assert!(config.rustdoc_compat);
+
+    std::fs::write(tmp_dir.path().join(".keeper.toml"), "rustdoc_compat = \n")?;
+    let error = crate::KeeperConfig::new(Some(&table), tmp_dir.path()).unwrap_err();
+    assert!(error.to_string().contains(".keeper.toml"));
+
+    Ok(())
+}
+
+#[test]
+fn prefix_suffix_and_readme() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("prefix_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let bookkeeper = BookKeeper::new();
+    let result = bookkeeper.real_run(None, root_tempdir.to_path_buf(), &mut book)?;
+    let mut files = result
+        .keys()
+        .map(|test| test.file.clone())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, ["afterword.md", "chapter_1.md", "preface.md"]);
+
+    let readme_path = root_tempdir.join("prefix_book").join("README.md");
+    let mut table = Table::new();
+    table.insert(
+        String::from("readme_path"),
+        Value::String(readme_path.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 4);
+    let readme_name = Path::new("prefix_book").join("README.md");
+    assert!(result
+        .iter()
+        .any(|(test, r)| test.file == readme_name.display().to_string()
+            && matches!(r, TestResult::Successful(_))));
+
+    // Outside the book's root, only the file name is used.
+    let outside = tempdir()?;
+    let readme_path = outside.path().join("README.md");
+    std::fs::copy(
+        root_tempdir.join("prefix_book").join("README.md"),
+        &readme_path,
+    )?;
+    table.insert(
+        String::from("readme_path"),
+        Value::String(readme_path.display().to_string()),
+    );
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert!(result.keys().any(|test| test.file == "README.md"));
+
+    Ok(())
+}
+
+#[test]
+fn compile_fail_crate_type() -> Result<(), Error> {
+    let source = || {
+        MarkdownSource(
+            "```rust,compile_fail\nfn f() -> u32 { \"no\" }\n```\n\n\
+             ```rust,compile_fail\n//@ crate_type: bin\nfn g() -> u32 { \"no\" }\n```\n",
+        )
+    };
+    let error_codes = |compile_fail_crate_type: Option<&str>| -> Result<Vec<Vec<String>>, Error> {
+        let tmp_dir = tempdir()?;
+        let mut table = Table::new();
+        if let Some(crate_type) = compile_fail_crate_type {
+            table.insert(
+                String::from("compile_fail_crate_type"),
+                Value::String(String::from(crate_type)),
+            );
+        }
+        BookKeeper::new().real_run_with_source(
+            Some(&table),
+            tmp_dir.path().to_path_buf(),
+            source(),
+        )?;
+        let error_codes = crate::cache::read_error_codes(&tmp_dir.path().join("doctest_cache"));
+        let mut error_codes = error_codes.into_iter().collect::<Vec<_>>();
+        error_codes.sort();
+        Ok(error_codes.into_iter().map(|(_, codes)| codes).collect())
+    };
+    let codes = |codes: &[&str]| {
+        codes
+            .iter()
+            .map(|code| code.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // As binaries, both blocks are also missing `main`.
+    assert_eq!(
+        error_codes(None)?,
+        [codes(&["E0308", "E0601"]), codes(&["E0308", "E0601"])]
+    );
+    // As a library, the first fails with only its own error.
+    assert_eq!(
+        error_codes(Some("lib"))?,
+        [codes(&["E0308"]), codes(&["E0308", "E0601"])]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn export_versions_path() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("edition_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cargo_dir = root_tempdir.join("edition_book").join("cargo");
+    let versions_path = root_tempdir.join("versions.json");
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("export_versions_path"),
+        Value::String(versions_path.display().to_string()),
+    );
+    // The versions come from cargo, so there must be a project.
+    let error = BookKeeper::new()
+        .real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)
+        .unwrap_err();
+    assert!(error.to_string().contains("needs manifest_dir"));
+
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    let versions: BTreeMap<String, String> =
+        serde_json::from_str(&std::fs::read_to_string(&versions_path)?)?;
+    assert_eq!(
+        versions.into_iter().collect::<Vec<_>>(),
+        [
+            (String::from("edition_book"), String::from("0.1.0")),
+            (String::from("helper"), String::from("0.1.0")),
+            (String::from("legacy"), String::from("0.1.0")),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn audit() -> Result<(), Error> {
+    use crate::TestSource;
+
+    let tmp_dir = tempdir()?;
+    let mut table = Table::new();
+    table.insert(String::from("audit"), Value::Boolean(true));
+    table.insert(String::from("skip_fragments"), Value::Boolean(true));
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+
+    let source = MarkdownSource(
+        "```rust\nfn main() {}\n```\n\n\
+         ```rust,ignore (needs a network)\nfn main() {}\n```\n\n\
+         ```rust\n    .map(|x| x + 1)\n```\n\n\
+         ```rust,no-such-os-only\nfn main() {}\n```\n",
+    );
+    let audit = crate::audit_tests(source.tests(&config)?, &config)
+        .into_iter()
+        .map(|(test, coverage)| (test.line, coverage.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        audit,
+        [
+            (2, String::from("tested")),
+            (6, String::from("ignored (needs a network)")),
+            (10, String::from("fragment")),
+            (14, String::from("skipped (not for this platform)")),
+        ]
+    );
+
+    // The blocks are audited as they'd be run, once for each edition.
+    table.insert(
+        String::from("test_editions"),
+        Value::Array(vec![
+            Value::String(String::from("2018")),
+            Value::String(String::from("2021")),
+        ]),
+    );
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    let audit = crate::audit_tests(source.tests(&config)?, &config)
+        .into_iter()
+        .map(|(test, _)| test.name)
+        .collect::<Vec<_>>();
+    assert_eq!(audit.len(), 8);
+    assert_eq!(audit[0], "markdown_source_line_1@2018");
+    assert_eq!(audit[1], "markdown_source_line_1@2021");
+
+    // Auditing a book doesn't touch the cache.
+    let (book_dir, book) = get_starting_directories("short_book")?;
+    let config = crate::KeeperConfig::new(Some(&table), book_dir.path())?;
+    crate::audit_tests((&book).tests(&config)?, &config);
+    assert!(!config.test_dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn failing_tests_are_not_left_in_the_cache() -> Result<(), Error> {
+    use crate::TestSource;
+
+    let tmp_dir = tempdir()?;
+    let config = crate::KeeperConfig::new(None, tmp_dir.path())?;
+    let source = MarkdownSource(
+        "```rust\nfn main() {}\n```\n\n```rust\nfn main() { panic!(\"no\") }\n```\n",
+    );
+
+    // Without the cleanup afterwards (as if keeper was stopped), only the
+    // test which passed is in the cache.
+    let results = crate::run_tests_with_config(
+        source.tests(&config)?,
+        &config,
+        &mut crate::Timings::default(),
+    );
+    assert_eq!(results.len(), 2);
+    let cached = crate::cache::cache_files(&config.test_dir, "keeper_*.rs").count();
+    assert_eq!(cached, 1);
+    let pending = crate::cache::cache_files(&config.test_dir, "pending_*.rs").count();
+    assert_eq!(pending, 0);
+
+    Ok(())
+}
+
+#[test]
+fn failing_tests_are_removed_from_a_shared_cache() -> Result<(), Error> {
+    use crate::TestSource;
+
+    let tmp_dir = tempdir()?;
+    let config = crate::KeeperConfig::new(None, tmp_dir.path())?;
+    std::fs::create_dir_all(&config.test_dir)?;
+    crate::cache::write_owner(&config.test_dir, "Another Book")?;
+
+    let source = MarkdownSource("```rust\nfn main() { panic!(\"no\") }\n```\n");
+    let results = crate::run_tests_with_config(
+        source.tests(&config)?,
+        &config,
+        &mut crate::Timings::default(),
+    );
+    // As if an older version of keeper had cached the failure.
+    let (test, _) = results.iter().next().expect("there is a test");
+    let stale = crate::get_test_path(test, &config);
+    std::fs::write(&stale, "fn main() { panic!(\"no\") }")?;
+
+    crate::cleanup_keepercache(&config, &results);
+    assert!(!stale.exists());
+
+    Ok(())
+}
+
+#[test]
+fn dependency_versions_come_from_dep_info() {
+    use crate::run_tests::version_from_dep_info;
+
+    let registry = "/target/debug/deps/serde_json-0a1b2c.d: \
+                    /registry/src/index.crates.io-1949cf8c6b5b557f/serde_json-1.0.108/src/lib.rs \
+                    /target/debug/build/serde_json-3d4e5f/out/private.rs\n";
+    assert_eq!(
+        version_from_dep_info(registry, "serde_json"),
+        Some(String::from("1.0.108"))
+    );
+    assert_eq!(version_from_dep_info(registry, "serde"), None);
+
+    // A path dependency's directory doesn't say its version.
+    let path = "/target/debug/deps/helper-0a1b2c.d: /book/cargo/helper/src/lib.rs\n";
+    assert_eq!(version_from_dep_info(path, "helper"), None);
+}
+
+#[test]
+fn editing_a_path_dependency_reruns_examples() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("path_dep_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cargo_dir = root_tempdir.join("path_dep_book").join("cargo");
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+
+    // cargo rebuilds the library in the same place, under the same name.
+    let greeter = cargo_dir.join("greeter").join("src").join("lib.rs");
+    let mut source = std::fs::read_to_string(&greeter)?;
+    source.push_str("\npub fn unused() {}\n");
+    std::fs::write(&greeter, source)?;
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let rerun = result
+        .values()
+        .filter(|r| !matches!(r, TestResult::Cached))
+        .count();
+    assert_eq!(rerun, 1);
+
+    Ok(())
+}
+
+#[test]
+fn directives_change_the_hash() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let config = crate::KeeperConfig::new(None, tmp_dir.path())?;
+    let hash = |markdown: &str| {
+        let tests = crate::tests_from_markdown(markdown, "markdown_source", &config);
+        tests[0].hash.clone()
+    };
+
+    let code = "fn main() { std::process::exit(3) }\n";
+    let plain = hash(&format!("```rust\n{}```\n", code));
+    let exit_3 = hash(&format!("```rust\n//@ exit: 3\n{}```\n", code));
+    let exit_4 = hash(&format!("```rust\n//@ exit: 4\n{}```\n", code));
+    assert_ne!(plain, exit_3);
+    assert_ne!(exit_3, exit_4);
+    // The same block is still the same test.
+    assert_eq!(
+        exit_3,
+        hash(&format!("```rust\n//@ exit: 3\n{}```\n", code))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rustdoc_compat_wraps_code_with_main_helpers() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = || {
+        MarkdownSource(
+            "```rust\nfn main_helper() {}\nmain_helper();\n```\n\n```rust\nfn main() {}\n```\n",
+        )
+    };
+    let run = |rustdoc_compat: bool| -> Result<Vec<crate::Outcome>, Error> {
+        let mut table = Table::new();
+        table.insert(
+            String::from("rustdoc_compat"),
+            Value::Boolean(rustdoc_compat),
+        );
+        let results = BookKeeper::new().real_run_with_source(
+            Some(&table),
+            tmp_dir.path().to_path_buf(),
+            source(),
+        )?;
+        let mut outcomes = results
+            .iter()
+            .map(|(test, result)| (test.line, result.kind()))
+            .collect::<Vec<_>>();
+        outcomes.sort_by_key(|(line, _)| *line);
+        Ok(outcomes.into_iter().map(|(_, outcome)| outcome).collect())
+    };
+
+    // `fn main_helper` isn't a `main`, so the code is still wrapped in one.
+    assert_eq!(
+        run(true)?,
+        [crate::Outcome::Successful, crate::Outcome::Successful]
+    );
+    // Turning `rustdoc_compat` off changes the code compiled, so nothing
+    // is cached.
+    assert_eq!(
+        run(false)?,
+        [crate::Outcome::CompileFailed, crate::Outcome::Successful]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn accumulate_repeated_uses_and_crate_attributes() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let mut table = Table::new();
+    table.insert(String::from("accumulate"), Value::Boolean(true));
+    table.insert(String::from("rustdoc_compat"), Value::Boolean(true));
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+
+    let source = MarkdownSource(
+        "```rust\nuse std::collections::HashMap;\n\
+         fn make() -> HashMap<u8, u8> { HashMap::new() }\n```\n\n\
+         ```rust\n#![allow(dead_code)]\nuse std::collections::HashMap;\n\
+         fn unused() -> HashMap<u8, u8> { make() }\n```\n\n\
+         ```rust\nfn main() {\n    assert!(unused().is_empty());\n}\n```\n\n\
+         ```rust\nstruct Again;\n```\n\n\
+         ```rust\nstruct Again;\n```\n",
+    );
+    let (results, _) = BookKeeper::new().run_with_config(&config, source)?;
+    let mut outcomes = results
+        .iter()
+        .map(|(test, result)| (test.line, result.kind()))
+        .collect::<Vec<_>>();
+    outcomes.sort_by_key(|(line, _)| *line);
+
+    // The repeated `use` is only made once, and the attribute is moved
+    // above the definitions before it; but an item defined twice clashes.
+    assert_eq!(
+        outcomes.into_iter().map(|(_, o)| o).collect::<Vec<_>>(),
+        [
+            crate::Outcome::Successful,
+            crate::Outcome::Successful,
+            crate::Outcome::Successful,
+            crate::Outcome::Successful,
+            crate::Outcome::CompileFailed,
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sanitizer_errors_are_not_panics() -> Result<(), Error> {
+    use crate::run_tests::is_sanitizer_error;
+    use std::process::{ExitStatus, Output};
+
+    let output = |stderr: &str| Output {
+        status: ExitStatus::default(),
+        stdout: vec![],
+        stderr: stderr.as_bytes().to_vec(),
+    };
+    let asan = output(
+        "==4242==ERROR: AddressSanitizer: heap-use-after-free on address 0x602000000010\n\
+         SUMMARY: AddressSanitizer: heap-use-after-free src/main.rs:4:20 in main\n",
+    );
+    assert!(is_sanitizer_error(&asan));
+    let panic = output("thread 'main' panicked at src/main.rs:2:5:\nexplicit panic\n");
+    assert!(!is_sanitizer_error(&panic));
+
+    // Even a block which expects to fail doesn't expect this.
+    let tmp_dir = tempdir()?;
+    let mut table = Table::new();
+    table.insert(
+        String::from("should_panic_any_failure"),
+        Value::Boolean(true),
+    );
+    let config = crate::KeeperConfig::new(Some(&table), tmp_dir.path())?;
+    let tests = crate::tests_from_markdown(
+        "```rust,should_panic\nfn main() {}\n```\n",
+        "sanitizer",
+        &config,
+    );
+    assert!(config.met_expectations(&tests[0], &TestResult::RunFailed(panic)));
+    assert!(!config.met_expectations(&tests[0], &TestResult::RunFailed(asan)));
+
+    Ok(())
+}
+
+#[test]
+fn incremental_state_is_reused_after_an_edit() -> Result<(), Error> {
+    let (tmp_dir, book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+    let incremental_dir = root_tempdir.join("doctest_cache").join("incremental");
+
+    let bookkeeper = BookKeeper::new();
+    let mut table = Table::new();
+    table.insert(String::from("incremental"), Value::Boolean(true));
+
+    // The crate directories rustc keeps in each test's incremental directory.
+    let sessions = || -> Result<BTreeMap<String, Vec<String>>, Error> {
+        let mut sessions = BTreeMap::new();
+        for test_dir in std::fs::read_dir(&incremental_dir)? {
+            let test_dir = test_dir?.path();
+            let mut crate_dirs = std::fs::read_dir(&test_dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>, std::io::Error>>()?;
+            crate_dirs.sort();
+            let name = test_dir.file_name().unwrap().to_string_lossy().into_owned();
+            sessions.insert(name, crate_dirs);
+        }
+        Ok(sessions)
+    };
+
+    let mut first_book = book.clone();
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut first_book)?;
+    assert_eq!(result.len(), 4);
+    let before = sessions()?;
+    assert_eq!(before.len(), 4);
+    assert!(before.values().all(|crate_dirs| crate_dirs.len() == 1));
+
+    // Every test's hash changes, but its name doesn't.
+    let mut edited_book = book;
+    edited_book.for_each_mut(|item| {
+        if let BookItem::Chapter(ref mut chapter) = item {
+            chapter.content = chapter
+                .content
+                .replace("fn main() {", "fn main() {\n    let _edited = ();");
+        }
+    });
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut edited_book)?;
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+    assert_eq!(sessions()?, before);
+
+    Ok(())
+}
+
+#[test]
+fn dependencies_without_an_rlib() -> Result<(), Error> {
+    use crate::run_tests::missing_dependency_artifacts;
+
+    let (tmp_dir, mut book) = get_starting_directories("artifact_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cargo_dir = root_tempdir.join("artifact_book").join("cargo");
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+
+    // The proc-macro's shared object is found, so the example can use it.
+    let result = BookKeeper::new().real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    assert_eq!(result.len(), 1);
+    assert!(result
+        .values()
+        .all(|r| matches!(r, TestResult::Successful(_))));
+
+    // A cdylib has no library examples could link to, which is what
+    // keeper warns about.
+    let config = crate::KeeperConfig::new(Some(&table), root_tempdir)?;
+    let missing = missing_dependency_artifacts(
+        &cargo_dir,
+        &config.target_dir,
+        config.target_triple(),
+        None,
+        false,
+    )
+    .map_err(|e| Error::msg(e.to_string()))?;
+    assert_eq!(missing, ["native"]);
+
+    Ok(())
+}
+
+#[test]
+fn verbose_results_name_each_test_file() -> Result<(), Error> {
+    let (tmp_dir, mut book) = get_starting_directories("nested_book")?;
+    let root_tempdir = tmp_dir.path();
+
+    let mut table = Table::new();
+    table.insert(String::from("verbose"), Value::Boolean(true));
+    let config = crate::KeeperConfig::new(Some(&table), root_tempdir)?;
+    let bookkeeper = BookKeeper::new();
+
+    let report = |result: &HashMap<crate::Test, TestResult>| -> Result<String, Error> {
+        let mut out = Vec::new();
+        crate::print_results(&mut out, result, &config, &crate::Timings::default())?;
+        Ok(String::from_utf8(out)?)
+    };
+
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let built = report(&result)?;
+    let result = bookkeeper.real_run(Some(&table), root_tempdir.to_path_buf(), &mut book)?;
+    let cached = report(&result)?;
+
+    assert_eq!(result.len(), 4);
+    for test in result.keys() {
+        let path = crate::get_test_path(test, &config).display().to_string();
+        assert!(built.contains(&format!("(built: {})", path)), "{}", built);
+        assert!(
+            cached.contains(&format!("(cached: {})", path)),
+            "{}",
+            cached
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn timings_are_recorded_and_printed() -> Result<(), Error> {
+    let (tmp_dir, book) = get_starting_directories("path_dep_book")?;
+    let root_tempdir = tmp_dir.path();
+    let cargo_dir = root_tempdir.join("path_dep_book").join("cargo");
+
+    let mut table = Table::new();
+    table.insert(
+        String::from("manifest_dir"),
+        Value::String(cargo_dir.display().to_string()),
+    );
+    let config = crate::KeeperConfig::new(Some(&table), root_tempdir)?;
+    let bookkeeper = BookKeeper::new();
+
+    let report = |results, timings| -> Result<String, Error> {
+        let mut out = Vec::new();
+        crate::print_results(&mut out, results, &config, timings)?;
+        Ok(String::from_utf8(out)?)
+    };
+
+    let (results, timings) = bookkeeper.run_with_config(&config, &book)?;
+    assert!(timings.build.is_some());
+    assert!(timings.tests > std::time::Duration::ZERO);
+    let printed = report(&results, &timings)?;
+    assert!(printed.contains("Built manifest_dir in"), "{}", printed);
+    assert!(printed.contains("Ran 2 tests in"), "{}", printed);
+
+    // Nothing runs the second time, so there's no time to report for it.
+    let (results, timings) = bookkeeper.run_with_config(&config, &book)?;
+    let printed = report(&results, &timings)?;
+    assert!(printed.contains("Built manifest_dir in"), "{}", printed);
+    assert!(!printed.contains("Ran "), "{}", printed);
+
+    // Without a manifest_dir, nothing is built.
+    let config = crate::KeeperConfig::new(None, root_tempdir)?;
+    let source = MarkdownSource("```rust\nfn main() {}\n```\n");
+    let (_, timings) = bookkeeper.run_with_config(&config, source)?;
+    assert!(timings.build.is_none());
+
+    Ok(())
+}
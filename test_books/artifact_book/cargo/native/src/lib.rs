@@ -0,0 +1,4 @@
+#[no_mangle]
+pub extern "C" fn native_answer() -> i32 {
+    42
+}
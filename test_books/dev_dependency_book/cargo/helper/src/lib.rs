@@ -0,0 +1,3 @@
+pub fn check(value: u32) {
+    assert_eq!(value, 42);
+}
@@ -0,0 +1,24 @@
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+// ANCHOR: add
+impl Point {
+    fn add(&self, other: &Point) -> Point {
+        Point {
+            x: self.x + other.x,
+            y: self.y + other.y,
+        }
+    }
+}
+// ANCHOR_END: add
+
+fn main() {
+    let a = Point { x: 1, y: 2 };
+    // ANCHOR: use
+    let b = a.add(&Point { x: 3, y: 4 });
+    // ANCHOR_END: use
+    assert_eq!(b, Point { x: 4, y: 6 });
+}
@@ -0,0 +1,53 @@
+use anyhow::Error;
+use mdbook_keeper_lib::{tests_from_markdown, BookKeeper, KeeperConfig, Outcome, Test, TestSource};
+use tempfile::tempdir;
+
+/// Doc comments from some other documentation tool, each of which is
+/// markdown.
+struct DocComments(Vec<(&'static str, &'static str)>);
+
+impl TestSource for DocComments {
+    fn tests(&self, config: &KeeperConfig) -> Result<Vec<Test>, Error> {
+        Ok(self
+            .0
+            .iter()
+            .flat_map(|(item, docs)| tests_from_markdown(docs, item, config))
+            .collect())
+    }
+}
+
+#[test]
+fn test_source_outside_the_crate() -> Result<(), Error> {
+    let tmp_dir = tempdir()?;
+    let source = DocComments(vec![
+        (
+            "add",
+            "Adds.\n\n```rust\nfn main() {\n    assert_eq!(1 + 1, 2);\n}\n```\n",
+        ),
+        (
+            "divide",
+            "Panics.\n\n```rust,should_panic\nfn main() {\n    panic!(\"zero\");\n}\n```\n",
+        ),
+    ]);
+
+    let results =
+        BookKeeper::new().real_run_with_source(None, tmp_dir.path().to_path_buf(), source)?;
+    let mut outcomes = results
+        .iter()
+        .map(|(test, result)| (test.file().to_string(), result.kind()))
+        .collect::<Vec<_>>();
+    outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        outcomes,
+        [
+            (String::from("add"), Outcome::Successful),
+            (String::from("divide"), Outcome::RunFailed),
+        ]
+    );
+    // A panic is what the second block expects.
+    assert!(results
+        .iter()
+        .all(|(test, result)| result.met_test_expectations(test, None)));
+
+    Ok(())
+}